@@ -0,0 +1,190 @@
+use std::{
+  future::Future,
+  io::{self, Read, Seek, SeekFrom},
+  pin::Pin,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  task::{ready, Context, Poll},
+  time::{Duration, Instant},
+};
+
+use futures::Stream;
+use tokio::{
+  io::{AsyncRead, ReadBuf},
+  time::Sleep,
+};
+
+/// Size of the chunks request bodies are sent in when built from memory.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Spreads the bytes sent by the uploads sharing it so they do not exceed a
+/// rate, in bytes per second.
+pub struct Limiter {
+  /// Bytes per second, or 0 for no limit.
+  rate: AtomicU64,
+  /// Time at which the bytes already reserved will have been sent.
+  next: Mutex<Instant>,
+}
+
+impl Limiter {
+  pub fn new(rate: Option<u64>) -> Limiter {
+    Limiter {
+      rate: AtomicU64::new(rate.unwrap_or(0)),
+      next: Mutex::new(Instant::now()),
+    }
+  }
+
+  pub fn rate(&self) -> Option<u64> {
+    Some(self.rate.load(Ordering::Relaxed)).filter(|rate| *rate > 0)
+  }
+
+  pub fn set_rate(&self, rate: Option<u64>) {
+    self.rate.store(rate.unwrap_or(0), Ordering::Relaxed);
+  }
+
+  /// Reserves bandwidth for the given amount of bytes, and returns how long to
+  /// wait before sending them.
+  fn reserve(&self, bytes: usize) -> Duration {
+    let rate = match self.rate() {
+      Some(rate) => rate,
+      None => return Duration::ZERO,
+    };
+
+    let mut next = self.next.lock().unwrap();
+    let now = Instant::now();
+    let start = (*next).max(now);
+
+    *next = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+
+    start - now
+  }
+}
+
+/// Limiters applying to one upload, the most restrictive one wins.
+#[derive(Clone, Default)]
+pub struct Throttle(Vec<Arc<Limiter>>);
+
+impl Throttle {
+  pub fn new(limiters: Vec<Arc<Limiter>>) -> Throttle {
+    Throttle(limiters)
+  }
+
+  /// Returns whether any of the limiters currently has a rate.
+  pub fn is_limited(&self) -> bool {
+    self.0.iter().any(|limiter| limiter.rate().is_some())
+  }
+
+  fn reserve(&self, bytes: usize) -> Duration {
+    self.0.iter().map(|limiter| limiter.reserve(bytes)).max().unwrap_or_default()
+  }
+
+  /// Waits until the given amount of bytes can be sent.
+  pub async fn acquire(&self, bytes: usize) {
+    let delay = self.reserve(bytes);
+
+    if !delay.is_zero() {
+      tokio::time::sleep(delay).await;
+    }
+  }
+
+  /// Waits until the given amount of bytes can be sent, from synchronous code
+  /// running on the runtime.
+  pub fn blocking_acquire(&self, bytes: usize) {
+    let delay = self.reserve(bytes);
+
+    if !delay.is_zero() {
+      tokio::task::block_in_place(|| std::thread::sleep(delay));
+    }
+  }
+
+  pub fn reader<R>(&self, inner: R) -> ThrottledReader<R> {
+    ThrottledReader {
+      inner,
+      throttle: self.clone(),
+      delay: None,
+    }
+  }
+
+  pub fn blocking_reader<R>(&self, inner: R) -> BlockingThrottledReader<R> {
+    BlockingThrottledReader { inner, throttle: self.clone() }
+  }
+
+  /// Splits a request body into chunks, released as bandwidth allows.
+  pub fn stream(&self, body: Vec<u8>) -> impl Stream<Item = Result<Vec<u8>, io::Error>> + Send + Sync + 'static {
+    let throttle = self.clone();
+
+    futures::stream::unfold((body, 0), move |(body, offset)| {
+      let throttle = throttle.clone();
+
+      async move {
+        if offset >= body.len() {
+          return None;
+        }
+
+        let end = (offset + CHUNK_SIZE).min(body.len());
+
+        throttle.acquire(end - offset).await;
+
+        Some((Ok(body[offset..end].to_vec()), (body, end)))
+      }
+    })
+  }
+}
+
+/// Reader pausing between reads so the bytes it returns follow the limits of
+/// a throttle.
+pub struct ThrottledReader<R> {
+  inner: R,
+  throttle: Throttle,
+  delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R> AsyncRead for ThrottledReader<R>
+where
+  R: AsyncRead + Unpin,
+{
+  fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    if let Some(delay) = self.delay.as_mut() {
+      ready!(delay.as_mut().poll(cx));
+
+      self.delay = None;
+    }
+
+    let before = buf.filled().len();
+
+    ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+    let delay = self.throttle.reserve(buf.filled().len() - before);
+
+    if !delay.is_zero() {
+      self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+    }
+
+    Poll::Ready(Ok(()))
+  }
+}
+
+/// Synchronous counterpart of `ThrottledReader`, for clients reading their
+/// body from a `Read` implementation.
+pub struct BlockingThrottledReader<R> {
+  inner: R,
+  throttle: Throttle,
+}
+
+impl<R: Read> Read for BlockingThrottledReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let count = self.inner.read(buf)?;
+
+    self.throttle.blocking_acquire(count);
+
+    Ok(count)
+  }
+}
+
+impl<R: Seek> Seek for BlockingThrottledReader<R> {
+  fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+    self.inner.seek(position)
+  }
+}
@@ -1,17 +1,27 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use serde::Deserialize;
+use serde_yaml::Value;
 
-use crate::uploaders::{
-  discord::{DiscordConfig, DiscordUploader},
-  dropbox::{DropboxConfig, DropboxUploader},
-  gdrive::{GoogleDriveConfig, GoogleDriveUploader},
-  imgur::{ImgurConfig, ImgurUploader},
-  noop::NoopUploader,
-  onedrive::{OneDriveConfig, OneDriveUploader},
-  s3::{S3Config, S3Uploader},
-  Uploader,
+use crate::{
+  bandwidth::Limiter,
+  connectivity::ConnectivityConfig,
+  games::GameModeConfig,
+  power::PowerConfig,
+  schedule::QuietHours,
+  secrets::{self, SecretStore, SecretsConfig},
+  transforms::{metadata::MetadataConfig, overlay::OverlayConfig, redact::RedactionConfig, thumbnail::ThumbnailConfig, Pipeline},
+  uploaders::{
+    discord::{DiscordConfig, DiscordUploader},
+    dropbox::{DropboxConfig, DropboxUploader},
+    gdrive::{GoogleDriveConfig, GoogleDriveUploader},
+    imgur::{ImgurConfig, ImgurUploader},
+    noop::NoopUploader,
+    onedrive::{OneDriveConfig, OneDriveUploader},
+    s3::{S3Config, S3Uploader},
+    Destination, Uploader,
+  },
 };
 
 #[derive(Clone, Deserialize)]
@@ -26,15 +36,117 @@ pub enum UploaderKind {
   Discord(DiscordConfig),
 }
 
+impl UploaderKind {
+  pub fn id(&self) -> &'static str {
+    match self {
+      UploaderKind::Noop => "noop",
+      UploaderKind::S3(_) => "s3",
+      UploaderKind::GoogleDrive(_) => "googledrive",
+      UploaderKind::Dropbox(_) => "dropbox",
+      UploaderKind::OneDrive(_) => "onedrive",
+      UploaderKind::Imgur(_) => "imgur",
+      UploaderKind::Discord(_) => "discord",
+    }
+  }
+
+  /// Replaces the secrets referenced as `secret:<name>` with their stored
+  /// value.
+  fn resolve_secrets(&self, secrets: &SecretStore) -> Result<UploaderKind, anyhow::Error> {
+    let mut kind = self.clone();
+
+    match kind {
+      UploaderKind::S3(ref mut config) => {
+        config.access_key_id = secrets.resolve(&config.access_key_id)?;
+        config.secret_access_key = secrets.resolve(&config.secret_access_key)?;
+      }
+
+      UploaderKind::OneDrive(ref mut config) => {
+        if let Some(ref mut client_secret) = config.client_secret {
+          *client_secret = secrets.resolve(client_secret)?;
+        }
+      }
+
+      UploaderKind::Imgur(ref mut config) => config.client_secret = secrets.resolve(&config.client_secret)?,
+      UploaderKind::Discord(ref mut config) => config.token = secrets.resolve(&config.token)?,
+      _ => {}
+    }
+
+    Ok(kind)
+  }
+
+  pub fn username(&self) -> Option<String> {
+    match self {
+      UploaderKind::Discord(config) => config.username.clone(),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct DestinationConfig {
+  pub name: Option<String>,
+  #[serde(flatten)]
+  pub uploader: UploaderKind,
+  pub overlay: Option<OverlayConfig>,
+  #[serde(default)]
+  pub redact: bool,
+  pub thumbnail: Option<ThumbnailConfig>,
+  /// Upload rate to this uploader, in bytes per second.
+  pub bandwidth_limit: Option<u64>,
+}
+
+impl DestinationConfig {
+  pub fn name(&self) -> String {
+    self.name.clone().unwrap_or_else(|| self.uploader.id().to_string())
+  }
+}
+
 #[derive(Clone, Deserialize)]
 pub struct Config {
   #[serde(default = "default_deckshot_path")]
   pub deckshot_path: PathBuf,
   #[serde(default = "default_screenshot_path")]
   pub screenshots_path: PathBuf,
-  pub uploader: UploaderKind,
+  pub uploader: Option<DestinationConfig>,
+  #[serde(default)]
+  pub uploaders: Vec<DestinationConfig>,
   #[serde(default = "default_retrier_interval")]
   pub retrier_interval: u64,
+  /// Interval between credential checks, in seconds, or 0 to disable them.
+  #[serde(default = "default_health_check_interval")]
+  pub health_check_interval: u64,
+  #[serde(default)]
+  pub metadata: MetadataConfig,
+  #[serde(default)]
+  pub redaction: RedactionConfig,
+  #[serde(default)]
+  pub secrets: SecretsConfig,
+  /// Time given to uploads in progress to finish when stopping, in seconds.
+  #[serde(default = "default_shutdown_grace_period")]
+  pub shutdown_grace_period: u64,
+  /// Location of the control socket, defaults to `deckshot.sock` in the
+  /// deckshot directory.
+  pub socket: Option<PathBuf>,
+  /// Time windows during which uploads are queued instead of sent.
+  #[serde(default)]
+  pub quiet_hours: Vec<QuietHours>,
+  /// Power conditions required to upload.
+  #[serde(default)]
+  pub power: PowerConfig,
+  /// Probe holding the queue of an uploader while it cannot be reached.
+  #[serde(default)]
+  pub connectivity: ConnectivityConfig,
+  /// Low-impact mode applied while a game is running.
+  #[serde(default)]
+  pub game_mode: GameModeConfig,
+  /// Upload rate shared by all uploaders, in bytes per second.
+  pub bandwidth_limit: Option<u64>,
+  /// File the upload activity is appended to, as one JSON object per line.
+  pub events_file: Option<PathBuf>,
+  /// Contents of the configuration file, to report the position of invalid
+  /// values.
+  #[serde(skip)]
+  pub source: String,
 }
 
 fn default_deckshot_path() -> PathBuf {
@@ -49,26 +161,320 @@ const fn default_retrier_interval() -> u64 {
   60
 }
 
+const fn default_health_check_interval() -> u64 {
+  3600
+}
+
+const fn default_shutdown_grace_period() -> u64 {
+  10
+}
+
 impl Config {
-  pub async fn uploader(&self) -> Result<Arc<Box<dyn Uploader>>, anyhow::Error> {
-    let uploader: Box<dyn Uploader> = match self.uploader {
+  pub fn socket_path(&self) -> PathBuf {
+    self.socket.clone().unwrap_or_else(|| self.deckshot_path.join("deckshot.sock"))
+  }
+
+  pub fn lock_path(&self) -> PathBuf {
+    self.deckshot_path.join("deckshot.pid")
+  }
+
+  /// Returns the configured destinations, the legacy `uploader` entry first,
+  /// along with their location in the configuration file.
+  pub fn destination_configs(&self) -> Vec<(String, &DestinationConfig)> {
+    self
+      .uploader
+      .iter()
+      .map(|destination| ("uploader".to_string(), destination))
+      .chain(self.uploaders.iter().enumerate().map(|(index, destination)| (format!("uploaders[{index}]"), destination)))
+      .collect()
+  }
+
+  pub async fn destinations(&self) -> Result<Vec<Arc<Destination>>, anyhow::Error> {
+    let configs = self.destination_configs();
+
+    if configs.is_empty() {
+      return Err(anyhow!("no uploader configured"));
+    }
+
+    let secrets = Arc::new(SecretStore::open(self).context("could not open secret store")?);
+    let mut names = HashSet::new();
+    let mut destinations = Vec::with_capacity(configs.len());
+
+    for (_, destination) in configs {
+      let name = destination.name();
+
+      if !names.insert(name.clone()) {
+        return Err(anyhow!("duplicate uploader name '{name}', set a unique 'name' on each uploader"));
+      }
+
+      destinations.push(Arc::new(self.destination(destination, &secrets).await?));
+    }
+
+    Ok(destinations)
+  }
+
+  /// Builds an uploader and its transform pipeline, without contacting the
+  /// service.
+  pub async fn destination(&self, destination: &DestinationConfig, secrets: &Arc<SecretStore>) -> Result<Destination, anyhow::Error> {
+    let name = destination.name();
+
+    // Credentials are stored in files named after the destination.
+    if !secrets::is_valid_name(&name) {
+      return Err(anyhow!("invalid uploader name '{name}'"));
+    }
+
+    let uploader = self.uploader(&destination.uploader, &name, secrets).await.context(format!("could not build uploader '{name}'"))?;
+    let pipeline = Pipeline::build(self, destination).context(format!("could not build transform pipeline for '{name}'"))?;
+
+    Ok(Destination {
+      name,
+      uploader,
+      pipeline,
+      limiter: Arc::new(Limiter::new(destination.bandwidth_limit)),
+    })
+  }
+
+  async fn uploader(&self, kind: &UploaderKind, name: &str, secrets: &Arc<SecretStore>) -> Result<Box<dyn Uploader>, anyhow::Error> {
+    let uploader: Box<dyn Uploader> = match kind.resolve_secrets(secrets)? {
       UploaderKind::Noop => Box::new(NoopUploader::build()?),
-      UploaderKind::S3(ref config) => Box::new(S3Uploader::build(config.clone())?),
-      UploaderKind::GoogleDrive(ref config) => Box::new(GoogleDriveUploader::build(config.clone()).await?),
-      UploaderKind::Dropbox(ref config) => Box::new(DropboxUploader::build(self, config.clone()).await?),
-      UploaderKind::OneDrive(ref config) => Box::new(OneDriveUploader::build(self, config.clone()).await?),
-      UploaderKind::Imgur(ref config) => Box::new(ImgurUploader::build(self, config.clone())?),
-      UploaderKind::Discord(ref config) => Box::new(DiscordUploader::build(self, config.clone()).await?),
+      UploaderKind::S3(config) => Box::new(S3Uploader::build(config)?),
+      UploaderKind::GoogleDrive(config) => Box::new(GoogleDriveUploader::build(secrets, name, config).await?),
+      UploaderKind::Dropbox(config) => Box::new(DropboxUploader::build(secrets, name, config).await?),
+      UploaderKind::OneDrive(config) => Box::new(OneDriveUploader::build(secrets, name, config).await?),
+      UploaderKind::Imgur(config) => Box::new(ImgurUploader::build(secrets, name, config)?),
+      UploaderKind::Discord(config) => Box::new(DiscordUploader::build(name, config)?),
     };
 
-    Ok(Arc::new(uploader))
+    Ok(uploader)
   }
 }
 
+pub fn config_path(path: Option<&PathBuf>) -> PathBuf {
+  path.cloned().unwrap_or_else(|| default_deckshot_path().join("deckshot.yml"))
+}
+
 pub fn read_config(path: Option<&PathBuf>) -> Result<Config, anyhow::Error> {
-  let default = default_deckshot_path().join("deckshot.yml");
-  let path = path.unwrap_or(&default);
-  let file = std::fs::File::open(path).context(format!("could not open configuration file: {}", path.display()))?;
+  let path = config_path(path);
+  let source = std::fs::read_to_string(&path).context(format!("could not open configuration file: {}", path.display()))?;
+
+  let mut value = serde_yaml::from_str::<Value>(&source).context(format!("could not parse configuration file: {}", path.display()))?;
+
+  interpolate_value(&mut value).context(format!("could not interpolate configuration file: {}", path.display()))?;
+
+  // Errors raised from a `Value` do not carry a position, it is looked up
+  // from the path of the offending value.
+  let mut config = serde_path_to_error::deserialize::<_, Config>(value)
+    .map_err(|err| {
+      let path = err.path().to_string();
+
+      match locate(&source, &path) {
+        Some((line, column)) => anyhow!("{path} (line {line}, column {column}): {}", err.inner()),
+        None => anyhow!("{path}: {}", err.inner()),
+      }
+    })
+    .context(format!("could not parse configuration file: {}", path.display()))?;
+
+  config.source = source;
+
+  Ok(config)
+}
+
+/// Returns the line and column of the value at a path such as
+/// `uploaders[1].thumbnail.size` in a block-style YAML document, or of the
+/// closest enclosing value that could be found.
+pub fn locate(source: &str, path: &str) -> Option<(usize, usize)> {
+  // Lines as (number, indentation, text), a line starting a sequence entry
+  // being split into a `-` marker and the content of the entry.
+  let mut lines = Vec::new();
+
+  for (number, line) in source.lines().enumerate() {
+    let mut indent = line.len() - line.trim_start().len();
+    let mut text = line.trim();
+
+    if text.is_empty() || text.starts_with('#') {
+      continue;
+    }
+
+    while let Some(rest) = text.strip_prefix('-').filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+      lines.push((number + 1, indent, "-"));
+
+      indent += rest.len() - rest.trim_start().len() + 1;
+      text = rest.trim_start();
+    }
+
+    if !text.is_empty() {
+      lines.push((number + 1, indent, text));
+    }
+  }
+
+  let mut scope = &lines[..];
+  let mut found = None;
+
+  for segment in path.split(['.', '[']).filter(|segment| !segment.is_empty()) {
+    let indent = match scope.first() {
+      Some(&(_, indent, _)) => indent,
+      None => break,
+    };
+
+    let (start, sequence) = match segment.strip_suffix(']').and_then(|index| index.parse::<usize>().ok()) {
+      Some(index) => (
+        scope.iter().enumerate().filter(|(_, &(_, i, text))| i == indent && text == "-").nth(index).map(|(start, _)| start),
+        true,
+      ),
+      None => (
+        scope
+          .iter()
+          .position(|&(_, i, text)| i == indent && text.strip_prefix(segment).map_or(false, |rest| rest == ":" || rest.starts_with(": "))),
+        false,
+      ),
+    };
+
+    let start = match start {
+      Some(start) => start,
+      None => break,
+    };
+
+    let (number, indent, _) = scope[start];
+    let rest = &scope[start + 1..];
+
+    // Sequences may be indented at the level of the key holding them.
+    let end = rest.iter().position(|&(_, i, text)| i < indent || (i == indent && (sequence || text != "-"))).unwrap_or(rest.len());
+
+    found = Some((number, indent + 1));
+    scope = &rest[..end];
+  }
+
+  found
+}
+
+fn interpolate_value(value: &mut Value) -> Result<(), anyhow::Error> {
+  match value {
+    Value::String(string) => {
+      // A value made of references only is read again as YAML, so that
+      // numbers and booleans keep their type, like `channel: ${CHANNEL_ID}`.
+      let reference_only = string.starts_with("${") && string.ends_with('}');
+      let interpolated = interpolate(string)?;
+
+      *value = match reference_only.then(|| serde_yaml::from_str::<Value>(&interpolated)) {
+        Some(Ok(scalar @ (Value::Number(_) | Value::Bool(_)))) => scalar,
+        _ => Value::String(interpolated),
+      };
+    }
+
+    Value::Sequence(items) => items.iter_mut().try_for_each(interpolate_value)?,
+    Value::Mapping(mapping) => mapping.iter_mut().try_for_each(|(_, value)| interpolate_value(value))?,
+    Value::Tagged(tagged) => interpolate_value(&mut tagged.value)?,
+    _ => {}
+  }
+
+  Ok(())
+}
+
+/// Substitutes `${VARIABLE}` with the value of an environment variable, and
+/// `${file:/path}` with the contents of a file, without its trailing newline.
+/// `$${` is kept as a literal `${`.
+fn interpolate(input: &str) -> Result<String, anyhow::Error> {
+  let mut output = String::with_capacity(input.len());
+  let mut rest = input;
+
+  while let Some(start) = rest.find("${") {
+    if rest[..start].ends_with('$') {
+      output.push_str(&rest[..start - 1]);
+      output.push_str("${");
+      rest = &rest[start + 2..];
+
+      continue;
+    }
+
+    output.push_str(&rest[..start]);
+
+    let end = rest[start..].find('}').ok_or_else(|| anyhow!("unterminated '${{' reference"))? + start;
+    let reference = &rest[start + 2..end];
+
+    let value = match reference.strip_prefix("file:") {
+      Some(path) => std::fs::read_to_string(path)
+        .context(format!("could not read file referenced as '${{{reference}}}'"))?
+        .trim_end_matches(['\r', '\n'])
+        .to_string(),
+      None => std::env::var(reference).context(format!("could not read environment variable referenced as '${{{reference}}}'"))?,
+    };
+
+    output.push_str(&value);
+    rest = &rest[end + 1..];
+  }
 
-  serde_yaml::from_reader::<_, Config>(file).context(format!("could not parse configuration file: {}", path.display()))
+  output.push_str(rest);
+
+  Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_yaml::Value;
+
+  use super::{interpolate, interpolate_value, locate};
+  use crate::testing::TempTree;
+
+  #[test]
+  fn substitutes_environment_variables() {
+    std::env::set_var("DECKSHOT_TEST_BUCKET", "screenshots");
+
+    assert_eq!(interpolate("s3://${DECKSHOT_TEST_BUCKET}/deck").unwrap(), "s3://screenshots/deck");
+  }
+
+  #[test]
+  fn keeps_escaped_references() {
+    assert_eq!(interpolate("$${HOME} costs $$5").unwrap(), "${HOME} costs $$5");
+  }
+
+  #[test]
+  fn rejects_unterminated_references() {
+    assert!(interpolate("${HOME").is_err());
+  }
+
+  #[test]
+  fn rejects_missing_variables() {
+    assert!(interpolate("${DECKSHOT_TEST_MISSING}").is_err());
+  }
+
+  #[test]
+  fn trims_trailing_newlines_of_files() {
+    let tree = TempTree::new("config-file-reference");
+    tree.write("secret", "  hunter2\r\n\n");
+
+    let reference = format!("${{file:{}}}", tree.root().join("secret").display());
+
+    assert_eq!(interpolate(&reference).unwrap(), "  hunter2");
+  }
+
+  #[test]
+  fn locates_values_by_path() {
+    let source = "uploaders:\n  - kind: S3\n    bucket: shots\n\n  # second destination\n  - kind: Discord\n    thumbnail:\n      size: -1\nquiet_hours:\n- start: \"22:00\"\n  end: \"22:00\"\n";
+
+    assert_eq!(locate(source, "uploaders[0].bucket"), Some((3, 5)));
+    assert_eq!(locate(source, "uploaders[1].thumbnail.size"), Some((8, 7)));
+    assert_eq!(locate(source, "quiet_hours[0].end"), Some((11, 3)));
+  }
+
+  #[test]
+  fn locates_closest_enclosing_value() {
+    let source = "uploader:\n  kind: Discord\n  channel: \"general\"\n";
+
+    assert_eq!(locate(source, "uploader.token"), Some((1, 1)));
+    assert_eq!(locate(source, "uploaders[0]"), None);
+  }
+
+  #[test]
+  fn keeps_scalar_types_of_references() {
+    std::env::set_var("DECKSHOT_TEST_CHANNEL", "1234");
+    std::env::set_var("DECKSHOT_TEST_REDACT", "true");
+
+    let mut value: Value = serde_yaml::from_str("channel: ${DECKSHOT_TEST_CHANNEL}\nredact: ${DECKSHOT_TEST_REDACT}\nusername: deck-${DECKSHOT_TEST_CHANNEL}").unwrap();
+
+    interpolate_value(&mut value).unwrap();
+
+    assert_eq!(value["channel"], Value::Number(1234.into()));
+    assert_eq!(value["redact"], Value::Bool(true));
+    assert_eq!(value["username"], Value::String("deck-1234".to_string()));
+  }
 }
@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use anyhow::{anyhow, Context};
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::net::TcpStream;
+
+#[derive(Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeKind {
+  /// Sends a HEAD request, any HTTP response means the service is reachable.
+  Head,
+  /// Opens a TCP connection to the host and port of the endpoint.
+  Tcp,
+  /// Never holds uploads.
+  None,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ConnectivityConfig {
+  #[serde(default = "default_probe")]
+  pub probe: ProbeKind,
+  /// Interval between probes while an uploader is unreachable, in seconds.
+  #[serde(default = "default_interval")]
+  pub interval: u64,
+  /// Time after which a probe fails, in seconds.
+  #[serde(default = "default_timeout")]
+  pub timeout: u64,
+}
+
+impl Default for ConnectivityConfig {
+  fn default() -> ConnectivityConfig {
+    ConnectivityConfig {
+      probe: default_probe(),
+      interval: default_interval(),
+      timeout: default_timeout(),
+    }
+  }
+}
+
+const fn default_probe() -> ProbeKind {
+  ProbeKind::Head
+}
+
+const fn default_interval() -> u64 {
+  15
+}
+
+const fn default_timeout() -> u64 {
+  5
+}
+
+impl ConnectivityConfig {
+  pub fn is_enabled(&self) -> bool {
+    self.probe != ProbeKind::None
+  }
+
+  /// Checks whether an endpoint can be reached.
+  pub async fn probe(&self, endpoint: &str) -> Result<(), anyhow::Error> {
+    let url = Url::parse(endpoint).context(format!("invalid endpoint '{endpoint}'"))?;
+    let timeout = Duration::from_secs(self.timeout);
+
+    match self.probe {
+      ProbeKind::None => Ok(()),
+
+      ProbeKind::Head => {
+        reqwest::Client::builder()
+          .timeout(timeout)
+          .build()?
+          .head(url)
+          .send()
+          .await
+          .context(format!("could not reach {endpoint}"))?;
+
+        Ok(())
+      }
+
+      ProbeKind::Tcp => {
+        let host = url.host_str().ok_or_else(|| anyhow!("endpoint '{endpoint}' has no host"))?;
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        tokio::time::timeout(timeout, TcpStream::connect((host, port)))
+          .await
+          .map_err(|_| anyhow!("connection to {host}:{port} timed out"))?
+          .context(format!("could not connect to {host}:{port}"))?;
+
+        Ok(())
+      }
+    }
+  }
+}
@@ -0,0 +1,303 @@
+use std::{
+  collections::HashMap,
+  fs,
+  os::unix::fs::FileTypeExt,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use kvlogger::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+  net::{
+    unix::{OwnedReadHalf, OwnedWriteHalf},
+    UnixListener, UnixStream,
+  },
+  sync::broadcast::error::RecvError,
+};
+
+use crate::{daemon::Daemon, steam::GameScreenshot};
+
+/// Requests are sent as one JSON object per line, such as
+/// `{"command": "upload", "path": "/path/to/screenshot.jpg"}`.
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+  Status,
+  Queue,
+  Recent,
+  /// Pauses uploads, for `duration` seconds if given.
+  Pause {
+    duration: Option<u64>,
+  },
+  Resume,
+  Retry,
+  Upload {
+    path: PathBuf,
+  },
+  /// Sets the bandwidth limit, in bytes per second, of `uploader` or of all
+  /// of them. The limit is removed when omitted.
+  Bandwidth {
+    limit: Option<u64>,
+    uploader: Option<String>,
+  },
+  Reload,
+  Subscribe,
+}
+
+/// Each request is answered with one JSON object on its own line.
+#[derive(Deserialize, Serialize)]
+struct Response {
+  ok: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  data: Option<Value>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  error: Option<String>,
+}
+
+impl Response {
+  fn ok(data: Option<Value>) -> Response {
+    Response { ok: true, data, error: None }
+  }
+
+  fn error(error: String) -> Response {
+    Response {
+      ok: false,
+      data: None,
+      error: Some(error),
+    }
+  }
+}
+
+/// Sends a request to the running daemon and returns the data it answered
+/// with.
+pub async fn request(path: &Path, request: Value) -> Result<Option<Value>, anyhow::Error> {
+  let stream = UnixStream::connect(path)
+    .await
+    .context(format!("could not connect to the control socket, is deckshot running? ({})", path.display()))?;
+
+  let (reader, mut writer) = stream.into_split();
+
+  send(&mut writer, &request).await?;
+
+  let line = BufReader::new(reader).lines().next_line().await?.ok_or_else(|| anyhow!("connection closed without a response"))?;
+  let response: Response = serde_json::from_str(&line).context("invalid response")?;
+
+  match response.error {
+    Some(error) => Err(anyhow!(error)),
+    None => Ok(response.data),
+  }
+}
+
+/// Creates the control socket, only accessible to the current user.
+///
+/// Its permissions are set through the umask, which applies to the whole
+/// process: this must be called before any task that may create files is
+/// started.
+pub async fn bind(path: &Path) -> Result<UnixListener, anyhow::Error> {
+  // A socket left by a previous instance prevents binding, but only a socket
+  // nobody answers on is removed.
+  if let Ok(metadata) = fs::symlink_metadata(path) {
+    if !metadata.file_type().is_socket() {
+      return Err(anyhow!("not a socket: {}", path.display()));
+    }
+
+    if UnixStream::connect(path).await.is_ok() {
+      return Err(anyhow!("socket is in use by another process: {}", path.display()));
+    }
+
+    fs::remove_file(path).context(format!("could not remove stale socket: {}", path.display()))?;
+  }
+
+  // The socket is created with restricted permissions, instead of having
+  // them changed once connections can already be made.
+  let umask = unsafe { libc::umask(0o177) };
+  let listener = UnixListener::bind(path);
+
+  unsafe { libc::umask(umask) };
+
+  let listener = listener.context(format!("could not listen on socket: {}", path.display()))?;
+
+  kvlog!(Info, "control socket listening", {
+      "path" => path.display()
+  });
+
+  Ok(listener)
+}
+
+/// Answers control requests received on the socket.
+pub async fn serve(daemon: Arc<Daemon>, listener: UnixListener) -> Result<(), anyhow::Error> {
+  loop {
+    let (stream, _) = listener.accept().await.context("could not accept connection")?;
+
+    tokio::spawn({
+      let daemon = daemon.clone();
+
+      async move {
+        if let Err(err) = handle(daemon, stream).await {
+          kvlog!(Warn, "control connection failed", {
+              "error" => format!("{err:#}")
+          });
+        }
+      }
+    });
+  }
+}
+
+async fn handle(daemon: Arc<Daemon>, stream: UnixStream) -> Result<(), anyhow::Error> {
+  let (reader, mut writer) = stream.into_split();
+  let mut lines = BufReader::new(reader).lines();
+
+  while let Some(line) = lines.next_line().await? {
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let response = match serde_json::from_str::<Request>(&line) {
+      Ok(Request::Subscribe) => {
+        send(&mut writer, &Response::ok(None)).await?;
+
+        return subscribe(&daemon, lines, writer).await;
+      }
+
+      Ok(request) => match execute(&daemon, request).await {
+        Ok(data) => Response::ok(data),
+        Err(err) => Response::error(format!("{err:#}")),
+      },
+
+      Err(err) => Response::error(format!("invalid request: {err}")),
+    };
+
+    send(&mut writer, &response).await?;
+  }
+
+  Ok(())
+}
+
+async fn send<T: Serialize>(writer: &mut OwnedWriteHalf, value: &T) -> Result<(), anyhow::Error> {
+  let mut payload = serde_json::to_vec(value)?;
+  payload.push(b'\n');
+
+  writer.write_all(&payload).await?;
+
+  Ok(())
+}
+
+/// Streams upload activity on the connection until the client closes it.
+/// Events missed by a slow client are skipped.
+async fn subscribe(daemon: &Daemon, mut lines: Lines<BufReader<OwnedReadHalf>>, mut writer: OwnedWriteHalf) -> Result<(), anyhow::Error> {
+  let mut events = daemon.events.subscribe();
+
+  loop {
+    tokio::select! {
+      record = events.recv() => match record {
+        Ok(record) => send(&mut writer, &*record).await?,
+        Err(RecvError::Lagged(_)) => continue,
+        Err(RecvError::Closed) => return Ok(()),
+      },
+
+      line = lines.next_line() => {
+        if line?.is_none() {
+          return Ok(());
+        }
+      }
+    }
+  }
+}
+
+async fn execute(daemon: &Arc<Daemon>, request: Request) -> Result<Option<Value>, anyhow::Error> {
+  match request {
+    Request::Status => {
+      let state = daemon.state();
+      let queues = daemon.queues().await;
+
+      Ok(Some(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "config": daemon.config_path,
+        "paused": daemon.is_paused(),
+        "paused_until": daemon.paused().and_then(|pause| pause.until).map(|until| until.to_rfc3339()),
+        "quiet_hours": daemon.is_quiet(),
+        "power": state.config.power.status(),
+        "offline": daemon.offline(),
+        "game": daemon.game(),
+        "bandwidth": bandwidth(daemon),
+        "held": daemon.held(),
+        "queued": queues.iter().map(|(_, paths)| paths.len()).sum::<usize>(),
+        "uploaders": state.destinations.iter().map(|destination| json!({
+          "name": destination.name,
+          "uploader": destination.uploader.name(),
+        })).collect::<Vec<_>>(),
+      })))
+    }
+
+    Request::Queue => Ok(Some(json!(daemon.queues().await.into_iter().collect::<HashMap<_, _>>()))),
+    Request::Recent => Ok(Some(json!(daemon.recent().await))),
+
+    Request::Pause { duration } => {
+      let until = daemon.pause(duration.map(Duration::from_secs));
+
+      Ok(Some(json!({ "until": until.map(|until| until.to_rfc3339()) })))
+    }
+
+    Request::Resume => {
+      daemon.resume();
+
+      Ok(None)
+    }
+
+    Request::Retry => {
+      daemon.retry_now();
+
+      Ok(None)
+    }
+
+    // Uploads run in the background, their outcome shows in `recent` and
+    // `queue`.
+    Request::Upload { path } => {
+      if !path.is_file() {
+        return Err(anyhow!("file does not exist: {}", path.display()));
+      }
+
+      tokio::spawn({
+        let daemon = daemon.clone();
+
+        async move {
+          let screenshot: GameScreenshot = path.into();
+
+          daemon.submit(&screenshot).await;
+        }
+      });
+
+      Ok(None)
+    }
+
+    Request::Bandwidth { limit, uploader } => {
+      daemon.set_bandwidth_limit(uploader.as_deref(), limit)?;
+
+      Ok(Some(bandwidth(daemon)))
+    }
+
+    Request::Reload => {
+      daemon.reload().await?;
+
+      Ok(None)
+    }
+
+    // Subscribing turns the connection into a stream of events, which only
+    // the connection handler can do.
+    Request::Subscribe => Err(anyhow!("subscriptions are handled by the connection")),
+  }
+}
+
+fn bandwidth(daemon: &Daemon) -> Value {
+  let (limit, uploaders) = daemon.bandwidth_limits();
+
+  json!({
+    "limit": limit,
+    "uploaders": uploaders.into_iter().collect::<HashMap<_, _>>(),
+  })
+}
@@ -0,0 +1,751 @@
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+  },
+  time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Local, Timelike};
+use kvlogger::*;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+  runtime::Handle,
+  sync::{watch, Mutex, Notify, Semaphore},
+};
+
+use crate::{
+  bandwidth::{Limiter, Throttle},
+  database::Db,
+  events::{self, ErrorKind, Event, Events},
+  games, power,
+  reload::{self, State},
+  schedule,
+  steam::GameScreenshot,
+  uploaders::{Destination, Health, Receipt},
+};
+
+/// Number of receipts kept for the control API.
+const RECENT_UPLOADS: usize = 50;
+
+/// How often uploads in progress are checked while shutting down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Uploads paused through the CLI or the control API, until resumed or until
+/// a deadline.
+#[derive(Clone, Copy)]
+pub struct Pause {
+  pub until: Option<DateTime<Local>>,
+}
+
+/// State of the running service, shared by the filesystem watcher, the
+/// background tasks and the control API.
+pub struct Daemon {
+  pub config_path: PathBuf,
+  pub db: Db,
+  pub events: Events,
+  state: watch::Sender<Arc<State>>,
+  watcher: std::sync::Mutex<RecommendedWatcher>,
+  recent: Mutex<VecDeque<Receipt>>,
+  paused: std::sync::Mutex<Option<Pause>>,
+  offline: std::sync::Mutex<HashSet<String>>,
+  game: std::sync::Mutex<Option<u64>>,
+  /// Bandwidth limit shared by all destinations.
+  limiter: Arc<Limiter>,
+  game_limiter: Arc<Limiter>,
+  /// Allows a single upload at a time while a game is running.
+  low_impact: Semaphore,
+  /// Runtime with low priority threads, running uploads while a game is
+  /// running.
+  background: Handle,
+  retry: Notify,
+  schedule: Notify,
+  shutting_down: AtomicBool,
+  /// Uploads in progress, as their queue and path, so they can be queued
+  /// again if interrupted. The same screenshot can be uploaded twice at once,
+  /// so they are identified by a counter.
+  in_flight: std::sync::Mutex<HashMap<u64, (String, String)>>,
+  next_upload: AtomicU64,
+}
+
+impl Daemon {
+  pub fn new(config_path: PathBuf, db: Db, state: State, watcher: RecommendedWatcher, background: Handle) -> Daemon {
+    let limiter = Arc::new(Limiter::new(state.config.bandwidth_limit));
+
+    Daemon {
+      config_path,
+      db,
+      events: Events::new(),
+      state: watch::channel(Arc::new(state)).0,
+      watcher: std::sync::Mutex::new(watcher),
+      recent: Mutex::new(VecDeque::with_capacity(RECENT_UPLOADS)),
+      paused: std::sync::Mutex::new(None),
+      offline: std::sync::Mutex::new(HashSet::new()),
+      game: std::sync::Mutex::new(None),
+      limiter,
+      game_limiter: Arc::new(Limiter::new(None)),
+      low_impact: Semaphore::new(1),
+      background,
+      retry: Notify::new(),
+      schedule: Notify::new(),
+      shutting_down: AtomicBool::new(false),
+      in_flight: std::sync::Mutex::new(HashMap::new()),
+      next_upload: AtomicU64::new(0),
+    }
+  }
+
+  pub fn state(&self) -> Arc<State> {
+    self.state.borrow().clone()
+  }
+
+  pub fn subscribe(&self) -> watch::Receiver<Arc<State>> {
+    self.state.subscribe()
+  }
+
+  /// Returns the current pause, unless it expired.
+  pub fn paused(&self) -> Option<Pause> {
+    let paused = *self.paused.lock().unwrap();
+
+    paused.filter(|pause| pause.until.map_or(true, |until| Local::now() < until))
+  }
+
+  pub fn is_paused(&self) -> bool {
+    self.paused().is_some()
+  }
+
+  pub fn is_quiet(&self) -> bool {
+    schedule::is_quiet(&self.state().config.quiet_hours, Local::now())
+  }
+
+  /// Returns why new uploads are currently queued instead of sent, if they
+  /// are.
+  pub fn held(&self) -> Option<&'static str> {
+    if self.shutting_down.load(Ordering::SeqCst) {
+      return Some("shutting down");
+    }
+
+    if self.is_paused() {
+      return Some("paused");
+    }
+
+    self.unmet_condition()
+  }
+
+  /// Returns the configured upload condition that is not currently met, if
+  /// any.
+  fn unmet_condition(&self) -> Option<&'static str> {
+    let current = self.state();
+
+    if schedule::is_quiet(&current.config.quiet_hours, Local::now()) {
+      return Some("quiet hours");
+    }
+
+    current.config.power.hold()
+  }
+
+  /// Returns the names of the destinations that could not be reached.
+  pub fn offline(&self) -> Vec<String> {
+    self.offline.lock().unwrap().iter().cloned().collect()
+  }
+
+  fn is_offline(&self, destination: &Destination) -> bool {
+    self.offline.lock().unwrap().contains(&destination.name)
+  }
+
+  fn set_offline(&self, destination: &Destination, offline: bool) {
+    let changed = match offline {
+      true => self.offline.lock().unwrap().insert(destination.name.clone()),
+      false => self.offline.lock().unwrap().remove(&destination.name),
+    };
+
+    if !changed {
+      return;
+    }
+
+    if offline {
+      kvlog!(Warn, "uploader is unreachable, holding its queue", {
+          "destination" => destination.name
+      });
+    } else {
+      kvlog!(Info, "uploader is reachable again, sending its queue", {
+          "destination" => destination.name
+      });
+
+      self.retry_now();
+    }
+  }
+
+  /// Probes the endpoint of a destination, and records whether it can be
+  /// reached.
+  async fn check_connectivity(&self, destination: &Destination) -> bool {
+    let current = self.state();
+    let connectivity = &current.config.connectivity;
+
+    let reachable = match destination.uploader.endpoint() {
+      Some(endpoint) if connectivity.is_enabled() => connectivity.probe(endpoint).await.is_ok(),
+      _ => true,
+    };
+
+    self.set_offline(destination, !reachable);
+
+    reachable
+  }
+
+  /// Returns the Steam app ID of the game running, as last detected.
+  pub fn game(&self) -> Option<u64> {
+    *self.game.lock().unwrap()
+  }
+
+  fn detect_game(&self) {
+    let game = self.state().config.game_mode.running_game();
+    let previous = std::mem::replace(&mut *self.game.lock().unwrap(), game);
+
+    if game == previous {
+      return;
+    }
+
+    match game {
+      Some(id) => {
+        kvlog!(Info, "game started, uploading in low-impact mode", {
+            "game" => id
+        });
+      }
+
+      None => {
+        kvlog!(Info, "game exited, sending deferred uploads", {
+            "game" => previous.unwrap_or_default()
+        });
+
+        self.retry_now();
+      }
+    }
+  }
+
+  /// Returns whether uploads to a destination wait for the running game to
+  /// exit, so its image processing does not compete with it.
+  fn defers(&self, destination: &Destination) -> bool {
+    self.game().is_some() && destination.pipeline.processes()
+  }
+
+  fn throttle(&self, destination: &Destination) -> Throttle {
+    let mut limiters = vec![self.limiter.clone(), destination.limiter.clone()];
+
+    if self.game().is_some() {
+      self.game_limiter.set_rate(self.state().config.game_mode.bandwidth_limit);
+
+      limiters.push(self.game_limiter.clone());
+    }
+
+    Throttle::new(limiters)
+  }
+
+  /// Returns the global bandwidth limit and the limit of each destination, in
+  /// bytes per second.
+  pub fn bandwidth_limits(&self) -> (Option<u64>, Vec<(String, Option<u64>)>) {
+    let destinations = self.state().destinations.iter().map(|destination| (destination.name.clone(), destination.limiter.rate())).collect();
+
+    (self.limiter.rate(), destinations)
+  }
+
+  /// Changes the global bandwidth limit, or the limit of one destination,
+  /// until the configuration is reloaded.
+  pub fn set_bandwidth_limit(&self, destination: Option<&str>, rate: Option<u64>) -> Result<(), anyhow::Error> {
+    let current = self.state();
+
+    let limiter = match destination {
+      Some(name) => {
+        &current
+          .destinations
+          .iter()
+          .find(|destination| destination.name == name)
+          .ok_or_else(|| anyhow!("no uploader named '{name}'"))?
+          .limiter
+      }
+
+      None => &self.limiter,
+    };
+
+    limiter.set_rate(rate);
+
+    kvlog!(Info, "bandwidth limit changed", {
+        "destination" => destination.unwrap_or_default(),
+        "limit" => rate.unwrap_or_default()
+    });
+
+    Ok(())
+  }
+
+  /// Pauses uploads, until resumed or for the given duration, and returns
+  /// when they will automatically resume.
+  pub fn pause(&self, duration: Option<Duration>) -> Option<DateTime<Local>> {
+    let until = duration
+      .and_then(|duration| chrono::Duration::from_std(duration).ok())
+      .and_then(|duration| Local::now().checked_add_signed(duration));
+
+    *self.paused.lock().unwrap() = Some(Pause { until });
+    self.schedule.notify_one();
+
+    kvlog!(Info, "uploads paused", {
+        "paused" => true,
+        "until" => until.map(|until| until.to_rfc3339()).unwrap_or_default()
+    });
+
+    until
+  }
+
+  pub fn resume(&self) {
+    *self.paused.lock().unwrap() = None;
+    self.retry_now();
+
+    kvlog!(Info, "uploads resumed", {
+        "paused" => false
+    });
+  }
+
+  /// Wakes the retrier up instead of waiting for the next interval.
+  pub fn retry_now(&self) {
+    self.retry.notify_one();
+  }
+
+  pub async fn recent(&self) -> Vec<Receipt> {
+    self.recent.lock().await.iter().cloned().collect()
+  }
+
+  /// Returns the screenshots waiting to be uploaded, per destination.
+  pub async fn queues(&self) -> Vec<(String, Vec<String>)> {
+    let db = self.db.lock().await;
+
+    self
+      .state()
+      .destinations
+      .iter()
+      .map(|destination| (destination.name.clone(), db.liter(&destination.queue()).filter_map(|item| item.get_item::<String>()).collect()))
+      .collect()
+  }
+
+  /// Sends a new screenshot to every destination, or queues it while uploads
+  /// are held, the destination is unreachable or its image processing is
+  /// deferred.
+  pub async fn submit(&self, screenshot: &GameScreenshot) {
+    self.events.emit(Event::ScreenshotDetected {
+      path: screenshot.path.clone(),
+      game_id: screenshot.game_id,
+    });
+
+    let held = self.held().is_some();
+
+    for destination in self.state().destinations.iter() {
+      if held || self.is_offline(destination) || self.defers(destination) {
+        if let Err(err) = screenshot.save(self.db.clone(), &destination.queue()).await {
+          kvlog!(Error, "could not queue screenshot", {
+              "error" => format!("{err:#}"),
+              "destination" => destination.name
+          });
+        }
+
+        continue;
+      }
+
+      self.upload(screenshot, destination).await;
+    }
+  }
+
+  pub async fn upload(&self, screenshot: &GameScreenshot, destination: &Arc<Destination>) {
+    self.events.emit(Event::UploadStarted {
+      path: screenshot.path.clone(),
+      destination: destination.name.clone(),
+    });
+
+    let id = self.next_upload.fetch_add(1, Ordering::Relaxed);
+
+    self.in_flight.lock().unwrap().insert(id, (destination.queue(), screenshot.path.to_string_lossy().into_owned()));
+
+    let result = match self.game() {
+      // While a game is running, uploads go one at a time through low priority
+      // threads.
+      Some(_) => {
+        let _permit = self.low_impact.acquire().await;
+
+        let task = self.background.spawn({
+          let throttle = self.throttle(destination);
+          let screenshot = screenshot.clone();
+          let destination = destination.clone();
+          let db = self.db.clone();
+          let events = self.events.clone();
+
+          async move { screenshot.upload(&destination, db, &events, &throttle).await }
+        });
+
+        task.await.context("upload task failed").and_then(|result| result)
+      }
+
+      None => screenshot.upload(destination, self.db.clone(), &self.events, &self.throttle(destination)).await,
+    };
+
+    // Failed uploads were queued again by now.
+    self.in_flight.lock().unwrap().remove(&id);
+
+    match result {
+      Ok(receipt) => {
+        kvlog!(Info, "screenshot uploaded", {
+            "path" => receipt.path.display(),
+            "game" => screenshot.game_name().await,
+            "destination" => receipt.destination,
+            "location" => receipt.location,
+            "thumbnail" => receipt.thumbnail.clone().unwrap_or_default()
+        });
+
+        let mut recent = self.recent.lock().await;
+
+        if recent.len() == RECENT_UPLOADS {
+          recent.pop_front();
+        }
+
+        recent.push_back(receipt.clone());
+
+        self.events.emit(Event::Uploaded { receipt });
+      }
+
+      Err(err) => {
+        kvlog!(Error, "could not upload screenshot", {
+            "error" => format!("{err:#}"),
+            "destination" => destination.name
+        });
+
+        let kind = ErrorKind::of(&err);
+
+        // Confirm the service is down before holding the next uploads.
+        if matches!(kind, ErrorKind::Network) {
+          self.check_connectivity(destination).await;
+        }
+
+        self.events.emit(Event::Failed {
+          path: screenshot.path.clone(),
+          destination: destination.name.clone(),
+          kind,
+          error: format!("{err:#}"),
+        });
+      }
+    }
+  }
+
+  /// Retries the failed uploads every `retrier_interval` seconds, or when
+  /// asked to.
+  pub async fn retrier(self: Arc<Self>) {
+    loop {
+      let current = self.state();
+
+      if self.held().is_none() {
+        for destination in current.destinations.iter() {
+          let queued = self.db.lock().await.llen(&destination.queue());
+
+          // Every upload would fail while the service cannot be reached.
+          if queued == 0 || self.defers(destination) || !self.check_connectivity(destination).await {
+            continue;
+          }
+
+          let mut paths: Vec<String> = Vec::new();
+
+          {
+            let db = self.db.lock().await;
+            let data = db.liter(&destination.queue());
+
+            for item in data {
+              if let Some(path) = item.get_item::<String>() {
+                kvlog!(Info, "uploading failed screenshot", {
+                    "path" => path,
+                    "destination" => destination.name
+                });
+
+                paths.push(path);
+              }
+            }
+          }
+
+          for path in paths.iter() {
+            // Uploads may have been paused, the service may have gone down or
+            // a game may have started while going through the queue.
+            if self.held().is_some() || self.is_offline(destination) || self.defers(destination) {
+              break;
+            }
+
+            let screenshot: GameScreenshot = PathBuf::from(path).into();
+
+            self.upload(&screenshot, destination).await;
+
+            let _ = self.db.lock().await.lrem_value(&destination.queue(), path);
+          }
+        }
+      }
+
+      tokio::select! {
+        _ = tokio::time::sleep(Duration::from_secs(current.config.retrier_interval)) => {}
+        _ = self.retry.notified() => {}
+      }
+    }
+  }
+
+  /// Probes the unreachable destinations every `connectivity.interval`
+  /// seconds, their queue is sent as soon as they can be reached again.
+  pub async fn connectivity_monitor(self: Arc<Self>) {
+    loop {
+      let current = self.state();
+
+      for destination in current.destinations.iter() {
+        if self.is_offline(destination) {
+          self.check_connectivity(destination).await;
+        }
+      }
+
+      tokio::time::sleep(Duration::from_secs(current.config.connectivity.interval.max(1))).await;
+    }
+  }
+
+  /// Resumes uploads when a timed pause expires, sends the queued screenshots
+  /// once the upload conditions are met again, and follows running games.
+  pub async fn scheduler(self: Arc<Self>) {
+    let mut unmet = self.unmet_condition();
+
+    self.detect_game();
+
+    loop {
+      let now = Local::now();
+      let current = self.state();
+
+      // Quiet hours are set to the minute.
+      let mut delay = Duration::from_secs(60 - u64::from(now.second()));
+
+      if current.config.power.is_enabled() {
+        delay = delay.min(power::POLL_INTERVAL);
+      }
+
+      if current.config.game_mode.enabled {
+        delay = delay.min(games::POLL_INTERVAL);
+      }
+
+      if let Some(until) = self.paused().and_then(|pause| pause.until) {
+        delay = delay.min((until - now).to_std().unwrap_or_default());
+      }
+
+      tokio::select! {
+        _ = tokio::time::sleep(delay) => {}
+        _ = self.schedule.notified() => {}
+      }
+
+      self.detect_game();
+
+      let expired = matches!(*self.paused.lock().unwrap(), Some(Pause { until: Some(until) }) if until <= Local::now());
+
+      if expired {
+        self.resume();
+      }
+
+      let condition = self.unmet_condition();
+
+      if condition != unmet {
+        match condition {
+          Some(reason) => {
+            kvlog!(Info, "holding uploads", {
+                "reason" => reason
+            });
+          }
+
+          None => {
+            kvlog!(Info, "upload conditions met, sending queued uploads", {
+                "reason" => unmet.unwrap_or_default()
+            });
+
+            self.retry_now();
+          }
+        }
+
+        unmet = condition;
+      }
+    }
+  }
+
+  /// Appends upload activity to the events file, reopening it when a reload
+  /// changes its location.
+  pub async fn events_writer(self: Arc<Self>) {
+    let mut state = self.subscribe();
+
+    loop {
+      let path = state.borrow().config.events_file.clone();
+
+      let writer = async {
+        match path.clone() {
+          Some(path) => events::write_to_file(self.events.subscribe(), path).await,
+          None => std::future::pending().await,
+        }
+      };
+
+      tokio::select! {
+        result = writer => match result {
+          Ok(()) => return,
+
+          // The file is opened again once a reload changes its location.
+          Err(err) => {
+            kvlog!(Error, "events file stopped", {
+                "error" => format!("{err:#}")
+            });
+
+            if events_file_changed(&mut state, &path).await.is_err() {
+              return;
+            }
+          }
+        },
+
+        result = events_file_changed(&mut state, &path) => {
+          if result.is_err() {
+            return;
+          }
+        }
+      }
+    }
+  }
+
+  /// Checks the credentials of every destination every
+  /// `health_check_interval` seconds.
+  pub async fn health_checker(self: Arc<Self>) {
+    let mut state = self.subscribe();
+
+    loop {
+      let current = state.borrow().clone();
+
+      if current.config.health_check_interval == 0 {
+        if state.changed().await.is_err() {
+          break;
+        }
+
+        continue;
+      }
+
+      for destination in current.destinations.iter() {
+        match destination.health().await {
+          Health::Valid => {}
+
+          Health::Expiring(remaining) => {
+            kvlog!(Warn, "credentials are expiring", {
+                "destination" => destination.name,
+                "remaining" => crate::format_duration(remaining),
+                "hint" => format!("run 'deckshot auth {}'", destination.name)
+            });
+          }
+
+          Health::Invalid { reason, hint } => {
+            kvlog!(Error, "credentials are invalid", {
+                "destination" => destination.name,
+                "error" => reason,
+                "hint" => hint
+            });
+          }
+        }
+      }
+
+      tokio::time::sleep(Duration::from_secs(current.config.health_check_interval)).await;
+    }
+  }
+
+  fn is_uploading(&self) -> bool {
+    !self.in_flight.lock().unwrap().is_empty()
+  }
+
+  /// Stops sending uploads, waits up to `shutdown_grace_period` seconds for
+  /// the ones in progress, then queues those that did not finish so they are
+  /// sent on the next start.
+  pub async fn shutdown(&self) {
+    self.shutting_down.store(true, Ordering::SeqCst);
+
+    let grace_period = Duration::from_secs(self.state().config.shutdown_grace_period);
+
+    kvlog!(Info, "shutting down", {
+        "uploads" => self.in_flight.lock().unwrap().len()
+    });
+
+    let _ = tokio::time::timeout(grace_period, async {
+      while self.is_uploading() {
+        tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+      }
+    })
+    .await;
+
+    let interrupted = std::mem::take(&mut *self.in_flight.lock().unwrap());
+    let mut db = self.db.lock().await;
+
+    for (queue, path) in interrupted.into_values() {
+      // Screenshots being retried are only removed from their queue once
+      // sent.
+      if db.liter(&queue).any(|item| item.get_item::<String>().as_deref() == Some(path.as_str())) {
+        continue;
+      }
+
+      match db.ladd(&queue, &path) {
+        Some(_) => {
+          kvlog!(Warn, "upload interrupted, queued for the next start", {
+              "path" => path,
+              "queue" => queue
+          });
+        }
+
+        None => {
+          kvlog!(Error, "could not queue interrupted upload", {
+              "path" => path,
+              "queue" => queue
+          });
+        }
+      }
+    }
+  }
+
+  /// Reloads the configuration file and swaps the uploaders in, keeping the
+  /// current ones if it is invalid.
+  pub async fn reload(&self) -> Result<(), anyhow::Error> {
+    let previous = self.state();
+    let state = reload::reload(&self.config_path, &self.db).await?;
+
+    if state.config.screenshots_path != previous.config.screenshots_path {
+      self.watch(&state.config.screenshots_path)?;
+
+      let _ = self.watcher.lock().unwrap().unwatch(&previous.config.screenshots_path);
+    }
+
+    for destination in state.destinations.iter() {
+      kvlog!(Info, "initialized uploader", {
+          "uploader" => destination.uploader.name(),
+          "name" => destination.name
+      });
+    }
+
+    kvlog!(Info, "configuration reloaded", {
+        "path" => self.config_path.display()
+    });
+
+    self.limiter.set_rate(state.config.bandwidth_limit);
+    self.state.send_replace(Arc::new(state));
+
+    Ok(())
+  }
+
+  pub fn watch(&self, path: &Path) -> Result<(), anyhow::Error> {
+    self
+      .watcher
+      .lock()
+      .unwrap()
+      .watch(path, RecursiveMode::Recursive)
+      .context(format!("could not watch screenshot directory: {}", path.display()))
+  }
+}
+
+/// Waits for a reload changing the location of the events file.
+async fn events_file_changed(state: &mut watch::Receiver<Arc<State>>, path: &Option<PathBuf>) -> Result<(), watch::error::RecvError> {
+  loop {
+    state.changed().await?;
+
+    if state.borrow().config.events_file != *path {
+      return Ok(());
+    }
+  }
+}
@@ -1,30 +1,67 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
+use kvlogger::*;
 use pickledb::{PickleDb, SerializationMethod};
-use tokio::{
-  fs::{create_dir_all, File},
-  io::{AsyncReadExt, AsyncWriteExt},
-  sync::Mutex,
-};
+use tokio::sync::Mutex;
 
-use crate::config::Config;
+use crate::{config::Config, uploaders::Destination};
 
 pub type Db = Arc<Mutex<PickleDb>>;
 
-pub fn init_db(config: &Config) -> Result<Db, anyhow::Error> {
+pub fn init_db(config: &Config, destinations: &[Arc<Destination>]) -> Result<Db, anyhow::Error> {
   let mut db = match load_db(config) {
     Ok(db) => db,
     Err(_) => create_db(config),
   };
 
-  if !db.lexists("screenshots") {
-    db.lcreate("screenshots").context("could not create database list")?;
+  create_lists(&mut db, destinations)?;
+
+  // Screenshots queued before destinations had their own list are retried on
+  // every destination.
+  if db.lexists("screenshots") {
+    let paths: Vec<String> = db.liter("screenshots").filter_map(|item| item.get_item::<String>()).collect();
+
+    for destination in destinations {
+      db.lextend(&destination.queue(), &paths).ok_or_else(|| anyhow!("could not migrate database list"))?;
+    }
+
+    db.lrem_list("screenshots").context("could not migrate database list")?;
   }
 
   Ok(Arc::new(Mutex::new(db)))
 }
 
+/// Creates the lists of destinations added by a configuration reload, the
+/// lists of removed destinations are kept until they are configured again,
+/// with a warning while they still hold screenshots.
+pub async fn create_queues(db: &Db, destinations: &[Arc<Destination>]) -> Result<(), anyhow::Error> {
+  create_lists(&mut *db.lock().await, destinations)
+}
+
+fn create_lists(db: &mut PickleDb, destinations: &[Arc<Destination>]) -> Result<(), anyhow::Error> {
+  for destination in destinations {
+    if !db.lexists(&destination.queue()) {
+      db.lcreate(&destination.queue()).context("could not create database list")?;
+    }
+  }
+
+  let queues: HashSet<String> = destinations.iter().map(|destination| destination.queue()).collect();
+
+  for queue in db.get_all() {
+    let count = db.llen(&queue);
+
+    if queue.starts_with("screenshots:") && !queues.contains(&queue) && count > 0 {
+      kvlog!(Warn, "queue of a removed uploader is kept until it is configured again", {
+        "queue" => queue,
+        "screenshots" => count
+      });
+    }
+  }
+
+  Ok(())
+}
+
 fn create_db(config: &Config) -> PickleDb {
   PickleDb::new(config.deckshot_path.join("deckshot.db"), pickledb::PickleDbDumpPolicy::AutoDump, SerializationMethod::Bin)
 }
@@ -36,24 +73,3 @@ fn load_db(config: &Config) -> Result<PickleDb, anyhow::Error> {
     SerializationMethod::Bin,
   )?)
 }
-
-pub async fn save_token(config: &Config, key: &str, value: &str) -> Result<(), anyhow::Error> {
-  create_dir_all(config.deckshot_path.join("credentials")).await?;
-
-  let mut file = File::create(config.deckshot_path.join("credentials").join(key)).await?;
-  file.write_all(value.as_bytes()).await?;
-
-  Ok(())
-}
-
-pub async fn load_token(config: &Config, key: &str) -> Result<String, anyhow::Error> {
-  let mut file = File::open(config.deckshot_path.join("credentials").join(key))
-    .await
-    .context("could not load token, did you run 'deckshot auth'?")?;
-
-  let mut value = String::new();
-
-  file.read_to_string(&mut value).await?;
-
-  Ok(value.trim().to_string())
-}
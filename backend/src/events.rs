@@ -0,0 +1,150 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use chrono::Local;
+use kvlogger::*;
+use serde::Serialize;
+use tokio::{
+  fs::OpenOptions,
+  io::AsyncWriteExt,
+  sync::broadcast::{self, error::RecvError},
+};
+
+use crate::uploaders::{tokens::AuthError, Receipt};
+
+/// Events not yet received by a slow subscriber are dropped past this count.
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+  Staging,
+  Uploading,
+  UploadingThumbnail,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+  /// Credentials are missing or were revoked.
+  Auth,
+  /// The service could not be reached.
+  Network,
+  /// The screenshot could not be read or processed.
+  File,
+  Other,
+}
+
+impl ErrorKind {
+  pub fn of(err: &anyhow::Error) -> ErrorKind {
+    for cause in err.chain() {
+      if cause.is::<AuthError>() {
+        return ErrorKind::Auth;
+      }
+
+      if cause.downcast_ref::<reqwest::Error>().map_or(false, |err| err.is_connect() || err.is_timeout()) {
+        return ErrorKind::Network;
+      }
+
+      if cause.is::<std::io::Error>() || cause.is::<image::ImageError>() {
+        return ErrorKind::File;
+      }
+    }
+
+    ErrorKind::Other
+  }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+  ScreenshotDetected {
+    path: PathBuf,
+    game_id: u64,
+  },
+  UploadStarted {
+    path: PathBuf,
+    destination: String,
+  },
+  /// Reports the stage an upload reached, not how much of it was sent.
+  Progress {
+    path: PathBuf,
+    destination: String,
+    stage: Stage,
+  },
+  Uploaded {
+    #[serde(flatten)]
+    receipt: Receipt,
+  },
+  Failed {
+    path: PathBuf,
+    destination: String,
+    kind: ErrorKind,
+    error: String,
+  },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Record {
+  pub time: String,
+  #[serde(flatten)]
+  pub event: Event,
+}
+
+/// Broadcasts upload activity to the control socket subscribers and the
+/// events file.
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<Arc<Record>>);
+
+impl Events {
+  pub fn new() -> Events {
+    Events(broadcast::channel(CAPACITY).0)
+  }
+
+  pub fn emit(&self, event: Event) {
+    // Sending only fails when nobody is listening.
+    let _ = self.0.send(Arc::new(Record {
+      time: Local::now().to_rfc3339(),
+      event,
+    }));
+  }
+
+  pub fn subscribe(&self) -> broadcast::Receiver<Arc<Record>> {
+    self.0.subscribe()
+  }
+}
+
+/// Appends every event to a file, as one JSON object per line.
+pub async fn write_to_file(mut events: broadcast::Receiver<Arc<Record>>, path: PathBuf) -> Result<(), anyhow::Error> {
+  let mut file = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(&path)
+    .await
+    .context(format!("could not open events file: {}", path.display()))?;
+
+  loop {
+    let record = match events.recv().await {
+      Ok(record) => record,
+
+      Err(RecvError::Lagged(count)) => {
+        kvlog!(Warn, "events were dropped from the events file", {
+            "count" => count
+        });
+
+        continue;
+      }
+
+      Err(RecvError::Closed) => return Ok(()),
+    };
+
+    let mut line = serde_json::to_vec(&*record)?;
+    line.push(b'\n');
+
+    file.write_all(&line).await.context("could not write to events file")?;
+
+    // Writes only complete in the background otherwise, and could be lost
+    // when stopping.
+    file.flush().await.context("could not write to events file")?;
+  }
+}
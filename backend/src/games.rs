@@ -0,0 +1,166 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio::runtime::{Builder, Handle};
+
+/// How often running processes are scanned for a game.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Niceness of the threads uploading while a game is running.
+const NICENESS: libc::c_int = 10;
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Low-impact mode applied while a game is running: one upload at a time,
+/// image processing deferred until the game exits, and uploads running at a
+/// low CPU and I/O priority.
+#[derive(Clone, Deserialize)]
+pub struct GameModeConfig {
+  #[serde(default = "default_enabled")]
+  pub enabled: bool,
+  /// Root of the procfs tree scanned for games.
+  #[serde(default = "default_proc_root")]
+  pub proc_root: PathBuf,
+  /// Upload rate while a game is running, in bytes per second.
+  pub bandwidth_limit: Option<u64>,
+}
+
+impl Default for GameModeConfig {
+  fn default() -> GameModeConfig {
+    GameModeConfig {
+      enabled: default_enabled(),
+      proc_root: default_proc_root(),
+      bandwidth_limit: None,
+    }
+  }
+}
+
+const fn default_enabled() -> bool {
+  true
+}
+
+fn default_proc_root() -> PathBuf {
+  "/proc".into()
+}
+
+impl GameModeConfig {
+  /// Returns the Steam app ID of the running game, if any.
+  pub fn running_game(&self) -> Option<u64> {
+    if !self.enabled {
+      return None;
+    }
+
+    running_game(&self.proc_root)
+  }
+}
+
+/// Steam starts games through its reaper, as in
+/// `reaper SteamLaunch AppId=1245620 -- ...`.
+fn running_game(proc_root: &Path) -> Option<u64> {
+  let processes = fs::read_dir(proc_root).ok()?;
+
+  for process in processes.flatten() {
+    if !process.file_name().to_string_lossy().chars().all(|character| character.is_ascii_digit()) {
+      continue;
+    }
+
+    let cmdline = match fs::read(process.path().join("cmdline")) {
+      Ok(cmdline) => cmdline,
+      Err(_) => continue,
+    };
+
+    let arguments: Vec<String> = cmdline.split(|byte| *byte == 0).map(|argument| String::from_utf8_lossy(argument).into_owned()).collect();
+
+    let reaper = arguments.first().map_or(false, |program| Path::new(program).file_name().map_or(false, |name| name == "reaper"));
+
+    if !reaper || !arguments.iter().any(|argument| argument == "SteamLaunch") {
+      continue;
+    }
+
+    if let Some(id) = arguments.iter().find_map(|argument| argument.strip_prefix("AppId=")).and_then(|id| id.parse().ok()) {
+      return Some(id);
+    }
+  }
+
+  None
+}
+
+/// Starts a runtime whose threads run at a low CPU and I/O priority, used for
+/// the uploads made while a game is running.
+pub fn background_runtime() -> Result<Handle, anyhow::Error> {
+  let runtime = Builder::new_multi_thread()
+    .worker_threads(1)
+    .thread_name("deckshot-background")
+    .on_thread_start(lower_priority)
+    .enable_all()
+    .build()
+    .context("could not start background runtime")?;
+
+  let handle = runtime.handle().clone();
+
+  // The runtime lives as long as the process, it cannot be dropped from
+  // within another one.
+  std::thread::spawn(move || runtime.block_on(std::future::pending::<()>()));
+
+  Ok(handle)
+}
+
+fn lower_priority() {
+  // On Linux, both calls only apply to the calling thread.
+  unsafe {
+    libc::setpriority(libc::PRIO_PROCESS, 0, NICENESS);
+    libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::running_game;
+  use crate::testing::TempTree;
+
+  fn process(procfs: &TempTree, pid: &str, arguments: &[&str]) {
+    procfs.write(format!("{pid}/cmdline"), arguments.iter().map(|argument| format!("{argument}\0")).collect::<String>());
+  }
+
+  #[test]
+  fn finds_game_started_by_reaper() {
+    let procfs = TempTree::new("games-reaper");
+
+    process(&procfs, "1", &["/sbin/init"]);
+    process(
+      &procfs,
+      "4242",
+      &["/home/deck/.steam/steam/ubuntu12_32/reaper", "SteamLaunch", "AppId=1245620", "--", "/games/eldenring.exe"],
+    );
+
+    assert_eq!(running_game(procfs.root()), Some(1245620));
+  }
+
+  #[test]
+  fn ignores_other_processes() {
+    let procfs = TempTree::new("games-other");
+
+    process(&procfs, "1", &["/sbin/init"]);
+    process(&procfs, "100", &["/usr/bin/steam", "SteamLaunch", "AppId=1245620"]);
+    process(&procfs, "101", &["reaper", "AppId=1245620"]);
+
+    assert_eq!(running_game(procfs.root()), None);
+  }
+
+  #[test]
+  fn ignores_non_process_directories() {
+    let procfs = TempTree::new("games-self");
+
+    process(&procfs, "self", &["reaper", "SteamLaunch", "AppId=1245620"]);
+    process(&procfs, "sys", &["reaper", "SteamLaunch", "AppId=1245620"]);
+
+    assert_eq!(running_game(procfs.root()), None);
+  }
+}
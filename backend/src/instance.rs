@@ -0,0 +1,89 @@
+use std::{
+  fs::{self, File, OpenOptions},
+  io::Write,
+  os::unix::io::AsRawFd,
+  path::Path,
+};
+
+use anyhow::{anyhow, Context};
+
+/// Lock held by the running service on a file holding its PID. The system
+/// releases it when the process exits, even if it crashed.
+///
+/// The lock is a POSIX record lock, so that it can be tested without being
+/// taken. Such locks are released when the process closes any descriptor of
+/// the file, which must not be opened elsewhere by the service.
+pub struct InstanceLock {
+  file: File,
+}
+
+impl InstanceLock {
+  /// Takes the lock, failing if another instance holds it.
+  pub fn acquire(path: &Path) -> Result<InstanceLock, anyhow::Error> {
+    if let Some(directory) = path.parent() {
+      fs::create_dir_all(directory).context(format!("could not create directory: {}", directory.display()))?;
+    }
+
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .open(path)
+      .context(format!("could not open lock file: {}", path.display()))?;
+
+    if !try_lock(&file) {
+      return Err(match holder(&file).or_else(|| read_pid(path)) {
+        Some(pid) => anyhow!("deckshot is already running (PID {pid}), use 'deckshot status', 'pause' or 'resume' to control it"),
+        None => anyhow!("deckshot is already running, use 'deckshot status', 'pause' or 'resume' to control it"),
+      });
+    }
+
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id()).context(format!("could not write lock file: {}", path.display()))?;
+
+    Ok(InstanceLock { file })
+  }
+
+  /// Returns the PID of the running instance, if there is one.
+  pub fn running(path: &Path) -> Option<u32> {
+    let file = File::open(path).ok()?;
+
+    holder(&file).map(|pid| read_pid(path).unwrap_or(pid))
+  }
+
+  /// Clears the PID before the lock is released. The file is kept, since
+  /// another instance may already be waiting to lock it.
+  pub fn release(self) {
+    let _ = self.file.set_len(0);
+  }
+}
+
+fn whole_file(kind: libc::c_int) -> libc::flock {
+  let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+
+  lock.l_type = kind as libc::c_short;
+  lock.l_whence = libc::SEEK_SET as libc::c_short;
+
+  lock
+}
+
+fn try_lock(file: &File) -> bool {
+  let lock = whole_file(libc::F_WRLCK);
+
+  unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &lock) == 0 }
+}
+
+/// Returns the PID of the process holding the lock, without taking it.
+fn holder(file: &File) -> Option<u32> {
+  let mut lock = whole_file(libc::F_WRLCK);
+
+  if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } != 0 || lock.l_type == libc::F_UNLCK as libc::c_short {
+    return None;
+  }
+
+  Some(lock.l_pid as u32)
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+  fs::read_to_string(path).ok()?.trim().parse().ok()
+}
@@ -3,27 +3,56 @@
 #[macro_use]
 extern crate async_trait;
 
+mod bandwidth;
 mod config;
+mod connectivity;
+mod control;
+mod daemon;
 mod database;
+mod events;
+mod games;
+mod instance;
+mod media;
+mod power;
+mod reload;
+mod schedule;
+mod secrets;
 mod steam;
+#[cfg(test)]
+mod testing;
+mod transforms;
 mod uploaders;
+mod validation;
 
 use std::{
   env,
+  io::{self, Write},
   path::{Path, PathBuf},
-  sync::mpsc::channel,
-  time::Duration,
+  sync::Arc,
+  time::{Duration, SystemTime},
 };
 
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use clap::{arg, value_parser, Command};
 use kvlogger::*;
 use notify::{
   event::{AccessKind, AccessMode},
   Event, EventKind, RecursiveMode, Watcher,
 };
+use serde_json::{json, Value};
+use tokio::{
+  signal::unix::{signal, SignalKind},
+  sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
 
-use crate::{steam::GameScreenshot, uploaders::Uploader};
+use crate::{
+  config::Config,
+  daemon::Daemon,
+  instance::InstanceLock,
+  reload::State,
+  steam::GameScreenshot,
+  uploaders::{Health, Uploader},
+};
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -35,21 +64,175 @@ async fn main() -> Result<(), anyhow::Error> {
 
   let args = Command::new("deckshot")
     .arg(arg!(-c --config <FILE> "Location of configuration file").required(false).value_parser(value_parser!(PathBuf)))
-    .subcommand(Command::new("auth").about("Launch an interactive authentication process"))
+    .subcommand(
+      Command::new("auth")
+        .about("Launch an interactive authentication process")
+        .arg(arg!([NAME] "Name of the uploader to authenticate, all of them if omitted"))
+        .arg(arg!(--check "Verify the credentials instead of authenticating")),
+    )
+    .subcommand(Command::new("check-config").about("Validate the configuration file without contacting any service"))
+    .subcommand(
+      Command::new("pause")
+        .about("Pause uploads on the running instance, new screenshots are queued")
+        .arg(arg!([DURATION] "Resume automatically after this duration, such as '45m' or '1h30m'")),
+    )
+    .subcommand(Command::new("reload").about("Reload the configuration file on the running instance"))
+    .subcommand(Command::new("resume").about("Resume uploads on the running instance"))
+    .subcommand(Command::new("retry").about("Retry the queued uploads on the running instance now"))
+    .subcommand(
+      Command::new("secret")
+        .about("Store an encrypted secret, referenced from the configuration as 'secret:<NAME>'")
+        .arg(arg!(<NAME> "Name of the secret")),
+    )
+    .subcommand(Command::new("status").about("Show the state of the running instance"))
+    .subcommand(
+      Command::new("upload")
+        .about("Upload a file through the running instance")
+        .arg(arg!(<PATH> "File to upload").value_parser(value_parser!(PathBuf))),
+    )
     .get_matches();
 
-  let config = config::read_config(args.get_one::<PathBuf>("config"))?;
-  let uploader = config.uploader().await.context("could not build uploader configuration")?;
+  let config_path = config::config_path(args.get_one::<PathBuf>("config"));
+  let config = config::read_config(Some(&config_path))?;
+
+  if args.subcommand_matches("check-config").is_some() {
+    let problems = validation::validate(&config).await;
+
+    for problem in problems.iter() {
+      println!("{problem}");
+    }
+
+    if !problems.is_empty() {
+      return Err(anyhow!("found {} problem(s) in the configuration", problems.len()));
+    }
+
+    println!("Configuration is valid");
+
+    return Ok(());
+  }
+
+  if let Some(args) = args.subcommand_matches("pause") {
+    let duration = args.get_one::<String>("DURATION").map(|duration| parse_duration(duration)).transpose()?;
+    let data = request(&config, json!({ "command": "pause", "duration": duration.map(|duration| duration.as_secs()) })).await?;
+
+    match data.as_ref().and_then(|data| data["until"].as_str()) {
+      Some(until) => println!("Uploads paused until {until}"),
+      None => println!("Uploads paused until resumed"),
+    }
+
+    return Ok(());
+  }
+
+  if args.subcommand_matches("resume").is_some() {
+    request(&config, json!({ "command": "resume" })).await?;
+
+    println!("Uploads resumed");
+
+    return Ok(());
+  }
+
+  if args.subcommand_matches("retry").is_some() {
+    request(&config, json!({ "command": "retry" })).await?;
+
+    println!("Retrying queued uploads");
+
+    return Ok(());
+  }
+
+  if args.subcommand_matches("reload").is_some() {
+    request(&config, json!({ "command": "reload" })).await?;
+
+    println!("Configuration reloaded");
+
+    return Ok(());
+  }
+
+  if args.subcommand_matches("status").is_some() {
+    let data = request(&config, json!({ "command": "status" })).await?;
 
-  kvlog!(Info, format!("initialized uploader: {}", uploader.name()));
+    println!("{}", serde_json::to_string_pretty(&data.unwrap_or_default())?);
 
-  if args.subcommand_matches("auth").is_some() {
-    uploader.auth().await?;
+    return Ok(());
+  }
+
+  if let Some(args) = args.subcommand_matches("upload") {
+    let path = args.get_one::<PathBuf>("PATH").unwrap();
+    let path = path.canonicalize().context(format!("could not find file: {}", path.display()))?;
+
+    request(&config, json!({ "command": "upload", "path": path })).await?;
+
+    println!("Uploading {}", path.display());
 
     return Ok(());
   }
 
-  let (tx, rx) = channel::<Event>();
+  if let Some(args) = args.subcommand_matches("secret") {
+    let name = args.get_one::<String>("NAME").unwrap();
+    let mut value = String::new();
+
+    print!("Value for '{name}': ");
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut value).context("could not read secret")?;
+
+    secrets::SecretStore::open(&config)?.save_secret(name, value.trim())?;
+    reload_running(&config).await;
+
+    return Ok(());
+  }
+
+  let destinations = config.destinations().await.context("could not build uploader configuration")?;
+
+  for destination in destinations.iter() {
+    kvlog!(Info, "initialized uploader", {
+        "uploader" => destination.uploader.name(),
+        "name" => destination.name
+    });
+  }
+
+  if let Some(args) = args.subcommand_matches("auth") {
+    let name = args.get_one::<String>("NAME");
+
+    if let Some(name) = name.filter(|name| !destinations.iter().any(|destination| &destination.name == *name)) {
+      return Err(anyhow!("no uploader named '{name}'"));
+    }
+
+    let selected = destinations.iter().filter(|destination| name.map(|name| &destination.name == name).unwrap_or(true));
+
+    if args.get_flag("check") {
+      let mut invalid = 0;
+
+      for destination in selected {
+        match destination.health().await {
+          Health::Valid => println!("{}: valid", destination.name),
+          Health::Expiring(remaining) => println!("{}: expiring in {}, run 'deckshot auth {}' to renew", destination.name, format_duration(remaining), destination.name),
+
+          Health::Invalid { reason, hint } => {
+            invalid += 1;
+
+            println!("{}: invalid ({reason}), {hint}", destination.name);
+          }
+        }
+      }
+
+      if invalid > 0 {
+        return Err(anyhow!("{invalid} uploader(s) have invalid credentials"));
+      }
+
+      return Ok(());
+    }
+
+    for destination in selected {
+      destination.uploader.auth().await.context(format!("could not authenticate '{}'", destination.name))?;
+    }
+
+    reload_running(&config).await;
+
+    return Ok(());
+  }
+
+  let lock = InstanceLock::acquire(&config.lock_path())?;
+  let config_path = config_path.canonicalize().unwrap_or(config_path);
+  let (tx, rx) = unbounded_channel::<Event>();
 
   let mut watcher = notify::recommended_watcher(move |event| {
     if let Ok(event) = event {
@@ -61,82 +244,217 @@ async fn main() -> Result<(), anyhow::Error> {
     .watch(Path::new(&config.screenshots_path), RecursiveMode::Recursive)
     .context(format!("could not watch screenshot directory: {}", config.screenshots_path.display()))?;
 
-  let db = database::init_db(&config).context("could not initialize database")?;
+  if let Some(directory) = config_path.parent() {
+    watcher
+      .watch(directory, RecursiveMode::NonRecursive)
+      .context(format!("could not watch configuration directory: {}", directory.display()))?;
+  }
 
-  tokio::spawn({
-    let db = db.clone();
-    let uploader = uploader.clone();
+  let db = database::init_db(&config, &destinations).context("could not initialize database")?;
+  let socket_path = config.socket_path();
+  let background = games::background_runtime()?;
+  let daemon = Arc::new(Daemon::new(config_path.clone(), db, State { config, destinations }, watcher, background));
 
-    async move {
-      loop {
-        let mut paths: Vec<String> = Vec::new();
+  // Bound before the tasks are started, see `control::bind`.
+  let listener = control::bind(&socket_path)
+    .await
+    .map_err(|err| {
+      kvlog!(Error, "could not open control socket", {
+          "error" => format!("{err:#}")
+      })
+    })
+    .ok();
 
-        {
-          let db = db.lock().await;
-          let data = db.liter("screenshots");
+  tokio::spawn(daemon.clone().retrier());
+  tokio::spawn(daemon.clone().health_checker());
+  tokio::spawn(daemon.clone().scheduler());
+  tokio::spawn(daemon.clone().connectivity_monitor());
+  tokio::spawn(daemon.clone().events_writer());
 
-          for item in data {
-            if let Some(path) = item.get_item::<String>() {
-              kvlog!(Info, "uploading failed screenshot", {
-                  "path" => path
-              });
+  // The plugin stops the service with SIGTERM.
+  let mut terminate = signal(SignalKind::terminate()).context("could not listen for SIGTERM")?;
+  let mut interrupt = signal(SignalKind::interrupt()).context("could not listen for SIGINT")?;
 
-              paths.push(path);
-            }
-          }
+  if let Some(listener) = listener {
+    tokio::spawn({
+      let daemon = daemon.clone();
+
+      async move {
+        if let Err(err) = control::serve(daemon, listener).await {
+          kvlog!(Error, "control socket stopped", {
+              "error" => format!("{err:#}")
+          });
         }
+      }
+    });
+  }
 
-        for path in paths.iter() {
-          let screenshot: GameScreenshot = PathBuf::from(path).into();
-
-          match screenshot.upload(&**uploader, db.clone()).await {
-            Ok(screenshot) => {
-              kvlog!(Info, "screenshot uploaded", {
-                  "path" => screenshot.path.display(),
-                  "game" => screenshot.game_name().await
-              });
-            }
-
-            Err(err) => {
-              kvlog!(Error, "could not upload screenshot", {
-                  "error" => format!("{err:#}")
-              });
-            }
-          }
+  tokio::spawn(handle_events(daemon.clone(), rx, config_path));
 
-          let _ = db.lock().await.lrem_value("screenshots", path);
-        }
+  tokio::select! {
+    _ = terminate.recv() => {}
+    _ = interrupt.recv() => {}
+  }
+
+  daemon.shutdown().await;
+
+  let _ = std::fs::remove_file(&socket_path);
+  lock.release();
+
+  kvlog!(Info, "stopped", {
+      "pid" => std::process::id()
+  });
+
+  // Returning drops the tasks still running, which removes their staged
+  // files.
+  Ok(())
+}
 
-        tokio::time::sleep(Duration::from_secs(config.retrier_interval)).await;
+/// Reloads the configuration when it is saved, and submits the screenshots
+/// written to the screenshot directory.
+async fn handle_events(daemon: Arc<Daemon>, mut rx: UnboundedReceiver<Event>, config_path: PathBuf) {
+  let mut last_modified = modified_at(&config_path);
+
+  while let Some(event) = rx.recv().await {
+    if reload::is_config_event(&event, &config_path) {
+      let modified = modified_at(&config_path);
+
+      // A single save usually triggers several events.
+      if modified.is_none() || modified == last_modified {
+        continue;
+      }
+
+      last_modified = modified;
+
+      if let Err(err) = daemon.reload().await {
+        kvlog!(Error, "could not reload configuration, keeping the current one", {
+            "error" => format!("{err:#}")
+        });
       }
+
+      continue;
     }
-  });
 
-  while let Ok(event) = rx.recv() {
     if event.kind == EventKind::Access(AccessKind::Close(AccessMode::Write)) {
       for path in event.paths {
         let screenshot: GameScreenshot = path.into();
         let lossy_path = screenshot.path.to_string_lossy();
 
         if lossy_path.ends_with(".jpg") && !lossy_path.contains("thumbnail") {
-          match screenshot.upload(&**uploader, db.clone()).await {
-            Ok(screenshot) => {
-              kvlog!(Info, "screenshot uploaded", {
-                  "path" => screenshot.path.display(),
-                  "game" => screenshot.game_name().await
-              });
-            }
-
-            Err(err) => {
-              kvlog!(Error, "could not upload screenshot", {
-                  "error" => format!("{err:#}")
-              });
-            }
-          }
+          daemon.submit(&screenshot).await;
         }
       }
     }
   }
+}
 
-  Ok(())
+/// Sends a request to the running instance, and returns the data it answered
+/// with.
+async fn request(config: &Config, request: Value) -> Result<Option<Value>, anyhow::Error> {
+  if InstanceLock::running(&config.lock_path()).is_none() {
+    return Err(anyhow!("deckshot is not running"));
+  }
+
+  control::request(&config.socket_path(), request).await
+}
+
+/// Lets the running instance, if any, pick up new credentials.
+async fn reload_running(config: &Config) {
+  if InstanceLock::running(&config.lock_path()).is_none() {
+    return;
+  }
+
+  match control::request(&config.socket_path(), json!({ "command": "reload" })).await {
+    Ok(_) => println!("Reloaded the running instance"),
+    Err(err) => println!("Could not reload the running instance ({err:#}), restart it to use the new credentials"),
+  }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn format_duration(duration: Duration) -> String {
+  let minutes = duration.as_secs() / 60;
+
+  format!("{}h{:02}m", minutes / 60, minutes % 60)
+}
+
+/// Parses durations such as `90s`, `45m` or `1h30m`, a plain number being
+/// seconds. Every part of a compound duration needs its unit.
+fn parse_duration(value: &str) -> Result<Duration, anyhow::Error> {
+  let invalid = || anyhow!("invalid duration '{value}', expected something like '45m' or '1h30m'");
+  let value = value.trim();
+
+  if let Ok(seconds) = value.parse::<u64>() {
+    return if seconds == 0 { Err(invalid()) } else { Ok(Duration::from_secs(seconds)) };
+  }
+
+  let mut seconds: u64 = 0;
+  let mut number = String::new();
+
+  for character in value.chars() {
+    let unit = match character {
+      '0'..='9' => {
+        number.push(character);
+
+        continue;
+      }
+
+      'h' => 3600,
+      'm' => 60,
+      's' => 1,
+      _ => return Err(invalid()),
+    };
+
+    let part = number.parse::<u64>().ok().and_then(|number| number.checked_mul(unit)).ok_or_else(invalid)?;
+
+    seconds = seconds.checked_add(part).ok_or_else(invalid)?;
+    number.clear();
+  }
+
+  if !number.is_empty() || seconds == 0 {
+    return Err(invalid());
+  }
+
+  Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::parse_duration;
+
+  #[test]
+  fn parses_durations() {
+    assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+    assert_eq!(parse_duration("45m").unwrap(), Duration::from_secs(45 * 60));
+    assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(90 * 60));
+    assert_eq!(parse_duration(" 2h ").unwrap(), Duration::from_secs(2 * 3600));
+  }
+
+  #[test]
+  fn rejects_components_without_unit() {
+    assert!(parse_duration("1h30").is_err());
+    assert!(parse_duration("1m5").is_err());
+  }
+
+  #[test]
+  fn rejects_invalid_durations() {
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("0").is_err());
+    assert!(parse_duration("0m").is_err());
+    assert!(parse_duration("h").is_err());
+    assert!(parse_duration("5d").is_err());
+    assert!(parse_duration("-5m").is_err());
+  }
+
+  #[test]
+  fn rejects_overflowing_durations() {
+    assert!(parse_duration(&format!("{}h", u64::MAX)).is_err());
+    assert!(parse_duration(&format!("{}s1s", u64::MAX)).is_err());
+    assert!(parse_duration("99999999999999999999s").is_err());
+  }
 }
@@ -0,0 +1,100 @@
+use std::{fs::File, io::Read, path::Path};
+
+use anyhow::Context;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MediaType {
+  pub mime: &'static str,
+  pub extension: Option<&'static str>,
+}
+
+pub const JPEG: MediaType = MediaType {
+  mime: "image/jpeg",
+  extension: Some("jpg"),
+};
+pub const PNG: MediaType = MediaType {
+  mime: "image/png",
+  extension: Some("png"),
+};
+pub const WEBP: MediaType = MediaType {
+  mime: "image/webp",
+  extension: Some("webp"),
+};
+pub const GIF: MediaType = MediaType {
+  mime: "image/gif",
+  extension: Some("gif"),
+};
+pub const MP4: MediaType = MediaType {
+  mime: "video/mp4",
+  extension: Some("mp4"),
+};
+pub const WEBM: MediaType = MediaType {
+  mime: "video/webm",
+  extension: Some("webm"),
+};
+pub const OCTET_STREAM: MediaType = MediaType {
+  mime: "application/octet-stream",
+  extension: None,
+};
+
+/// Magic bytes, along with the offset they are found at. All parts of a signature must match.
+const SIGNATURES: &[(&[(usize, &[u8])], MediaType)] = &[
+  (&[(0, b"\xff\xd8\xff")], JPEG),
+  (&[(0, b"\x89PNG\r\n\x1a\n")], PNG),
+  (&[(0, b"RIFF"), (8, b"WEBP")], WEBP),
+  (&[(0, b"GIF87a")], GIF),
+  (&[(0, b"GIF89a")], GIF),
+  (&[(0, b"\x1a\x45\xdf\xa3")], WEBM),
+];
+
+/// Major brands of ISO base media files that are MP4 video. Others, like HEIC, AVIF or QuickTime, share the `ftyp` box.
+const MP4_BRANDS: &[&[u8]] = &[b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"dash", b"M4V ", b"mmp4", b"MSNV"];
+
+pub fn detect(header: &[u8]) -> MediaType {
+  let matches = |offset: usize, magic: &[u8]| header.get(offset..offset + magic.len()) == Some(magic);
+
+  if matches(4, b"ftyp") {
+    let mp4 = MP4_BRANDS.iter().any(|brand| matches(8, brand));
+    return if mp4 { MP4 } else { OCTET_STREAM };
+  }
+
+  SIGNATURES
+    .iter()
+    .find(|(parts, _)| parts.iter().all(|(offset, magic)| matches(*offset, magic)))
+    .map(|(_, media_type)| *media_type)
+    .unwrap_or(OCTET_STREAM)
+}
+
+pub fn detect_file(path: &Path) -> Result<MediaType, anyhow::Error> {
+  let mut header = Vec::with_capacity(16);
+
+  File::open(path)
+    .and_then(|file| file.take(16).read_to_end(&mut header))
+    .context(format!("could not read file: {}", path.display()))?;
+
+  Ok(detect(&header))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn detects_mp4_by_major_brand() {
+    assert_eq!(detect(b"\0\0\0\x20ftypisom\0\0\x02\0"), MP4);
+    assert_eq!(detect(b"\0\0\0\x18ftypmp42\0\0\0\0"), MP4);
+  }
+
+  #[test]
+  fn ignores_other_iso_media_brands() {
+    assert_eq!(detect(b"\0\0\0\x18ftypheic\0\0\0\0"), OCTET_STREAM);
+    assert_eq!(detect(b"\0\0\0\x1cftypavif\0\0\0\0"), OCTET_STREAM);
+    assert_eq!(detect(b"\0\0\0\x14ftypqt  \0\0\0\0"), OCTET_STREAM);
+  }
+
+  #[test]
+  fn requires_riff_container_for_webp() {
+    assert_eq!(detect(b"RIFF\x24\0\0\0WEBPVP8 "), WEBP);
+    assert_eq!(detect(b"\0\0\0\0\0\0\0\0WEBPVP8 "), OCTET_STREAM);
+  }
+}
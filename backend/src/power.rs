@@ -0,0 +1,203 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How often the power supplies are read while conditions are configured.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Deserialize)]
+pub struct PowerConfig {
+  /// Only upload while plugged in.
+  #[serde(default)]
+  pub require_ac_power: bool,
+  /// Only upload on battery above this charge level.
+  pub min_battery_percent: Option<u8>,
+  /// Root of the sysfs tree power supplies are read from.
+  #[serde(default = "default_sysfs_root")]
+  pub sysfs_root: PathBuf,
+}
+
+impl Default for PowerConfig {
+  fn default() -> PowerConfig {
+    PowerConfig {
+      require_ac_power: false,
+      min_battery_percent: None,
+      sysfs_root: default_sysfs_root(),
+    }
+  }
+}
+
+fn default_sysfs_root() -> PathBuf {
+  "/sys".into()
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct PowerStatus {
+  pub ac_power: Option<bool>,
+  pub battery_percent: Option<u8>,
+}
+
+impl PowerConfig {
+  pub fn is_enabled(&self) -> bool {
+    self.require_ac_power || self.min_battery_percent.is_some()
+  }
+
+  pub fn status(&self) -> PowerStatus {
+    read(&self.sysfs_root.join("class/power_supply"))
+  }
+
+  /// Returns why uploads should wait, if the power conditions are not met.
+  pub fn hold(&self) -> Option<&'static str> {
+    if !self.is_enabled() {
+      return None;
+    }
+
+    let status = self.status();
+
+    // Devices without a battery can only run on AC power.
+    if status.ac_power.unwrap_or(status.battery_percent.is_none()) {
+      return None;
+    }
+
+    if self.require_ac_power {
+      return Some("on battery power");
+    }
+
+    match (self.min_battery_percent, status.battery_percent) {
+      (Some(minimum), Some(percent)) if percent < minimum => Some("battery low"),
+      _ => None,
+    }
+  }
+}
+
+fn read(directory: &Path) -> PowerStatus {
+  let mut status = PowerStatus::default();
+  let mut charging = false;
+
+  let supplies = match fs::read_dir(directory) {
+    Ok(supplies) => supplies,
+    Err(_) => return status,
+  };
+
+  for supply in supplies.flatten() {
+    let path = supply.path();
+    let attribute = |name: &str| fs::read_to_string(path.join(name)).ok().map(|value| value.trim().to_string());
+
+    match attribute("type").as_deref() {
+      Some("Mains") | Some("USB") => {
+        if let Some(online) = attribute("online") {
+          status.ac_power = Some(status.ac_power.unwrap_or(false) || online == "1");
+        }
+      }
+
+      // Batteries of peripherals, such as controllers, are reported with a
+      // "Device" scope.
+      Some("Battery") if attribute("scope").as_deref() != Some("Device") => {
+        if let Some(capacity) = attribute("capacity").and_then(|capacity| capacity.parse::<u8>().ok()) {
+          status.battery_percent = Some(status.battery_percent.map_or(capacity, |percent| percent.min(capacity)));
+        }
+
+        charging |= matches!(attribute("status").as_deref(), Some("Charging") | Some("Full"));
+      }
+
+      _ => {}
+    }
+  }
+
+  // Some devices only report the charging state of their battery.
+  if status.ac_power.is_none() && status.battery_percent.is_some() {
+    status.ac_power = Some(charging);
+  }
+
+  status
+}
+
+#[cfg(test)]
+mod tests {
+  use super::PowerConfig;
+  use crate::testing::TempTree;
+
+  fn supply(sysfs: &TempTree, name: &str, attributes: &[(&str, &str)]) {
+    for (attribute, value) in attributes {
+      sysfs.write(format!("class/power_supply/{name}/{attribute}"), format!("{value}\n"));
+    }
+  }
+
+  fn config(sysfs: &TempTree, require_ac_power: bool, min_battery_percent: Option<u8>) -> PowerConfig {
+    PowerConfig {
+      require_ac_power,
+      min_battery_percent,
+      sysfs_root: sysfs.root().to_owned(),
+    }
+  }
+
+  #[test]
+  fn reads_ac_power() {
+    let sysfs = TempTree::new("power-ac");
+
+    supply(&sysfs, "ACAD", &[("type", "Mains"), ("online", "1")]);
+    supply(&sysfs, "BAT1", &[("type", "Battery"), ("capacity", "12"), ("status", "Charging")]);
+
+    let status = config(&sysfs, true, None).status();
+
+    assert_eq!(status.ac_power, Some(true));
+    assert_eq!(status.battery_percent, Some(12));
+    assert_eq!(config(&sysfs, true, Some(50)).hold(), None);
+  }
+
+  #[test]
+  fn holds_on_battery() {
+    let sysfs = TempTree::new("power-battery");
+
+    supply(&sysfs, "ACAD", &[("type", "Mains"), ("online", "0")]);
+    supply(&sysfs, "BAT1", &[("type", "Battery"), ("capacity", "40"), ("status", "Discharging")]);
+
+    assert_eq!(config(&sysfs, true, None).hold(), Some("on battery power"));
+    assert_eq!(config(&sysfs, false, Some(50)).hold(), Some("battery low"));
+    assert_eq!(config(&sysfs, false, Some(30)).hold(), None);
+  }
+
+  #[test]
+  fn charging_battery_without_ac_supply_counts_as_ac_power() {
+    let sysfs = TempTree::new("power-charging");
+
+    supply(&sysfs, "BAT1", &[("type", "Battery"), ("capacity", "20"), ("status", "Charging")]);
+
+    assert_eq!(config(&sysfs, true, None).status().ac_power, Some(true));
+    assert_eq!(config(&sysfs, true, Some(50)).hold(), None);
+  }
+
+  #[test]
+  fn ignores_device_batteries() {
+    let sysfs = TempTree::new("power-device");
+
+    supply(&sysfs, "BAT1", &[("type", "Battery"), ("capacity", "80"), ("status", "Discharging")]);
+    supply(&sysfs, "controller", &[("type", "Battery"), ("scope", "Device"), ("capacity", "5"), ("status", "Charging")]);
+
+    let status = config(&sysfs, false, Some(50)).status();
+
+    assert_eq!(status.battery_percent, Some(80));
+    assert_eq!(status.ac_power, Some(false));
+    assert_eq!(config(&sysfs, false, Some(50)).hold(), None);
+  }
+
+  #[test]
+  fn missing_sysfs_does_not_hold() {
+    let root = TempTree::new("power-missing");
+    let config = PowerConfig {
+      require_ac_power: true,
+      min_battery_percent: Some(50),
+      sysfs_root: root.root().join("sys"),
+    };
+
+    let status = config.status();
+
+    assert_eq!(status.ac_power, None);
+    assert_eq!(status.battery_percent, None);
+    assert_eq!(config.hold(), None);
+  }
+}
@@ -0,0 +1,50 @@
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use anyhow::{anyhow, Context};
+use notify::{
+  event::{AccessKind, AccessMode},
+  Event, EventKind,
+};
+
+use crate::{
+  config::{self, Config},
+  database::{self, Db},
+  uploaders::Destination,
+  validation,
+};
+
+/// Configuration and uploaders in use, replaced as a whole when the
+/// configuration file changes.
+pub struct State {
+  pub config: Config,
+  pub destinations: Vec<Arc<Destination>>,
+}
+
+/// Returns whether a filesystem event touched the configuration file. Editors
+/// often replace the file instead of writing to it, so creations count too.
+pub fn is_config_event(event: &Event, path: &Path) -> bool {
+  let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Access(AccessKind::Close(AccessMode::Write)));
+
+  relevant && event.paths.iter().any(|changed| changed == path)
+}
+
+/// Reads and validates the configuration file, then builds the uploaders it
+/// describes. Queued screenshots are kept in the database, and lists are
+/// created for new destinations.
+pub async fn reload(path: &Path, db: &Db) -> Result<State, anyhow::Error> {
+  let config = config::read_config(Some(&PathBuf::from(path)))?;
+  let problems = validation::validate(&config).await;
+
+  if !problems.is_empty() {
+    return Err(anyhow!(problems.iter().map(ToString::to_string).collect::<Vec<_>>().join("; ")));
+  }
+
+  let destinations = config.destinations().await.context("could not build uploader configuration")?;
+
+  database::create_queues(db, &destinations).await?;
+
+  Ok(State { config, destinations })
+}
@@ -0,0 +1,110 @@
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use serde::{de::Error, Deserialize, Deserializer};
+
+/// A time window during which uploads are held, such as `22:00` to `07:00`.
+/// Windows ending before they start span midnight.
+#[derive(Clone, Deserialize)]
+pub struct QuietHours {
+  #[serde(deserialize_with = "time")]
+  pub start: NaiveTime,
+  #[serde(deserialize_with = "time")]
+  pub end: NaiveTime,
+  /// Days the window starts on, every day if empty.
+  #[serde(default, deserialize_with = "weekdays")]
+  pub days: Vec<Weekday>,
+}
+
+impl QuietHours {
+  pub fn contains(&self, now: DateTime<Local>) -> bool {
+    let time = now.time();
+    let today = now.weekday();
+
+    if self.start <= self.end {
+      self.starts_on(today) && time >= self.start && time < self.end
+    } else {
+      (self.starts_on(today) && time >= self.start) || (self.starts_on(today.pred()) && time < self.end)
+    }
+  }
+
+  fn starts_on(&self, day: Weekday) -> bool {
+    self.days.is_empty() || self.days.contains(&day)
+  }
+}
+
+pub fn is_quiet(windows: &[QuietHours], now: DateTime<Local>) -> bool {
+  windows.iter().any(|window| window.contains(now))
+}
+
+fn time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let value = String::deserialize(deserializer)?;
+
+  NaiveTime::parse_from_str(&value, "%H:%M").map_err(|_| D::Error::custom(format!("invalid time '{value}', expected HH:MM")))
+}
+
+fn weekdays<'de, D>(deserializer: D) -> Result<Vec<Weekday>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  Vec::<String>::deserialize(deserializer)?
+    .iter()
+    .map(|day| day.parse::<Weekday>().map_err(|_| D::Error::custom(format!("invalid day '{day}'"))))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::{DateTime, Local, NaiveTime, TimeZone, Weekday};
+
+  use super::QuietHours;
+
+  fn window(start: (u32, u32), end: (u32, u32), days: Vec<Weekday>) -> QuietHours {
+    QuietHours {
+      start: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+      end: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+      days,
+    }
+  }
+
+  /// January 2024 starts on a Monday, away from daylight saving changes.
+  fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+    Local.with_ymd_and_hms(2024, 1, day, hour, minute, 0).unwrap()
+  }
+
+  #[test]
+  fn contains_same_day_window() {
+    let window = window((9, 0), (17, 0), vec![]);
+
+    assert!(window.contains(at(1, 9, 0)));
+    assert!(window.contains(at(1, 12, 30)));
+    assert!(!window.contains(at(1, 17, 0)));
+    assert!(!window.contains(at(1, 8, 59)));
+  }
+
+  #[test]
+  fn contains_window_across_midnight() {
+    let window = window((22, 0), (7, 0), vec![]);
+
+    assert!(window.contains(at(1, 22, 0)));
+    assert!(window.contains(at(1, 23, 59)));
+    assert!(window.contains(at(2, 0, 0)));
+    assert!(window.contains(at(2, 6, 59)));
+    assert!(!window.contains(at(2, 7, 0)));
+    assert!(!window.contains(at(2, 12, 0)));
+    assert!(!window.contains(at(2, 21, 59)));
+  }
+
+  #[test]
+  fn window_across_midnight_belongs_to_its_start_day() {
+    // Friday night only, the 5th.
+    let window = window((22, 0), (7, 0), vec![Weekday::Fri]);
+
+    assert!(window.contains(at(5, 23, 0)));
+    assert!(window.contains(at(6, 3, 0)));
+    assert!(!window.contains(at(5, 3, 0)));
+    assert!(!window.contains(at(6, 23, 0)));
+    assert!(!window.contains(at(7, 3, 0)));
+  }
+}
@@ -0,0 +1,200 @@
+use std::{
+  env,
+  fs::{self, OpenOptions},
+  io::{ErrorKind, Write},
+  os::unix::fs::{DirBuilderExt, OpenOptionsExt},
+  path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use argon2::Argon2;
+use chacha20poly1305::{aead::Aead, KeyInit, XChaCha20Poly1305, XNonce};
+use serde::Deserialize;
+
+use crate::config::Config;
+
+/// Header of encrypted files, files without it were written by previous
+/// versions in plaintext.
+const MAGIC: &[u8] = b"DKS1";
+const NONCE_LENGTH: usize = 24;
+const PASSPHRASE_VAR: &str = "DECKSHOT_PASSPHRASE";
+
+/// Prefix of configuration values referencing a stored secret by name.
+pub const REFERENCE_PREFIX: &str = "secret:";
+
+#[derive(Clone, Default, Deserialize)]
+pub struct SecretsConfig {
+  /// Defaults to `secret.key` in the deckshot directory, created on first use.
+  pub key_file: Option<PathBuf>,
+  /// Derive the key from the passphrase given in `DECKSHOT_PASSPHRASE`
+  /// instead of using a key file.
+  #[serde(default)]
+  pub passphrase: bool,
+}
+
+/// Encrypted storage for OAuth tokens and the secrets referenced from the
+/// configuration, kept under the `credentials` directory.
+pub struct SecretStore {
+  path: PathBuf,
+  cipher: XChaCha20Poly1305,
+}
+
+impl SecretStore {
+  pub fn open(config: &Config) -> Result<SecretStore, anyhow::Error> {
+    SecretStore::with_key(config, true)
+  }
+
+  /// Opens the store without creating its key file or salt when they are
+  /// missing, in which case nothing stored can be decrypted.
+  pub fn open_read_only(config: &Config) -> Result<SecretStore, anyhow::Error> {
+    SecretStore::with_key(config, false)
+  }
+
+  fn with_key(config: &Config, create: bool) -> Result<SecretStore, anyhow::Error> {
+    let key = if config.secrets.passphrase {
+      derive_key(config, create)?
+    } else {
+      let path = config.secrets.key_file.clone().unwrap_or_else(|| config.deckshot_path.join("secret.key"));
+
+      load_or_create(&path, 32, create).context(format!("could not load key file: {}", path.display()))?
+    };
+
+    Ok(SecretStore {
+      path: config.deckshot_path.join("credentials"),
+      cipher: XChaCha20Poly1305::new_from_slice(&key).map_err(|_| anyhow!("invalid secret key"))?,
+    })
+  }
+
+  /// Opens a store kept in the given directory, with a fixed key.
+  #[cfg(test)]
+  pub fn at(path: &Path) -> SecretStore {
+    SecretStore {
+      path: path.to_path_buf(),
+      cipher: XChaCha20Poly1305::new_from_slice(&[0; 32]).unwrap(),
+    }
+  }
+
+  pub fn exists(&self, key: &str) -> bool {
+    self.path.join(key).exists()
+  }
+
+  pub fn save(&self, key: &str, value: &str) -> Result<(), anyhow::Error> {
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    let ciphertext = self.cipher.encrypt(XNonce::from_slice(&nonce), value.as_bytes()).map_err(|_| anyhow!("could not encrypt '{key}'"))?;
+
+    let mut data = Vec::with_capacity(MAGIC.len() + NONCE_LENGTH + ciphertext.len());
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&nonce);
+    data.extend(ciphertext);
+
+    write_private(&self.path.join(key), &data).context(format!("could not save '{key}'"))
+  }
+
+  pub fn remove(&self, key: &str) -> Result<(), anyhow::Error> {
+    fs::remove_file(self.path.join(key)).context(format!("could not remove '{key}'"))
+  }
+
+  pub fn load(&self, key: &str) -> Result<String, anyhow::Error> {
+    let data = fs::read(self.path.join(key)).context("could not load token, did you run 'deckshot auth'?")?;
+
+    let value = match data.strip_prefix(MAGIC) {
+      Some(data) if data.len() >= NONCE_LENGTH => {
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+
+        self
+          .cipher
+          .decrypt(XNonce::from_slice(nonce), ciphertext)
+          .map_err(|_| anyhow!("could not decrypt '{key}', was the key or passphrase changed?"))?
+      }
+
+      Some(_) => return Err(anyhow!("'{key}' is corrupted")),
+
+      // Plaintext files get encrypted the next time they are saved.
+      None => data,
+    };
+
+    Ok(String::from_utf8(value).context(format!("'{key}' is not valid UTF-8"))?.trim().to_string())
+  }
+
+  /// Stores a secret that can be referenced from the configuration as
+  /// `secret:<name>`.
+  pub fn save_secret(&self, name: &str, value: &str) -> Result<(), anyhow::Error> {
+    self.save(&secret_key(name)?, value)
+  }
+
+  /// Returns the stored secret a configuration value references, or the value
+  /// itself if it is not a reference.
+  pub fn resolve(&self, value: &str) -> Result<String, anyhow::Error> {
+    match value.strip_prefix(REFERENCE_PREFIX) {
+      Some(name) => self.load(&secret_key(name)?).context(format!("could not load secret '{name}', store it with 'deckshot secret {name}'")),
+      None => Ok(value.to_string()),
+    }
+  }
+}
+
+/// Returns whether a name can be used for a file of the credentials
+/// directory, without pointing outside of it.
+pub fn is_valid_name(name: &str) -> bool {
+  !name.is_empty() && !name.contains(['/', '\\']) && !name.starts_with('.')
+}
+
+fn secret_key(name: &str) -> Result<String, anyhow::Error> {
+  if !is_valid_name(name) {
+    return Err(anyhow!("invalid secret name '{name}'"));
+  }
+
+  Ok(format!("secrets/{name}"))
+}
+
+/// Writes a file readable by its owner only, through a temporary file so that
+/// an interrupted write never leaves a truncated file behind.
+fn write_private(path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+  if let Some(parent) = path.parent() {
+    fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+  }
+
+  let mut temp = path.as_os_str().to_owned();
+  temp.push(".tmp");
+
+  let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&temp)?;
+
+  file.write_all(data)?;
+  file.sync_all()?;
+
+  fs::rename(&temp, path)?;
+
+  Ok(())
+}
+
+/// Reads random bytes from a file, generating them first if it does not
+/// exist. Unless `create` is set, the generated bytes are not saved.
+fn load_or_create(path: &Path, len: usize, create: bool) -> Result<Vec<u8>, anyhow::Error> {
+  match fs::read(path) {
+    Ok(data) => Ok(data),
+
+    Err(err) if err.kind() == ErrorKind::NotFound => {
+      let data: Vec<u8> = (0..len).map(|_| rand::random()).collect();
+
+      if create {
+        write_private(path, &data)?;
+      }
+
+      Ok(data)
+    }
+
+    Err(err) => Err(err.into()),
+  }
+}
+
+fn derive_key(config: &Config, create: bool) -> Result<Vec<u8>, anyhow::Error> {
+  let passphrase = env::var(PASSPHRASE_VAR).context(format!("secrets are protected by a passphrase, but {PASSPHRASE_VAR} is not set"))?;
+  let salt = load_or_create(&config.deckshot_path.join("secret.salt"), 16, create).context("could not load passphrase salt")?;
+
+  let mut key = vec![0; 32];
+
+  Argon2::default()
+    .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+    .map_err(|err| anyhow!("could not derive key from passphrase: {err}"))?;
+
+  Ok(key)
+}
@@ -5,11 +5,20 @@ use std::{
 };
 
 use anyhow::anyhow;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use kvlogger::*;
 use serde::Deserialize;
 
-use crate::{database::Db, Uploader};
+use crate::{
+  bandwidth::Throttle,
+  database::Db,
+  events::{Event, Events, Stage},
+  media::{self, MediaType},
+  uploaders::{Destination, Receipt},
+};
 
 const APPS: &[(u64, &str)] = &[(7, "Steam UI")];
+const STEAMID64_BASE: u64 = 76561197960265728;
 
 #[derive(Deserialize)]
 struct GameInformationResponse {
@@ -21,9 +30,12 @@ struct GameInformation {
   name: String,
 }
 
+#[derive(Clone)]
 pub struct GameScreenshot {
   pub game_id: u64,
+  pub user_id: Option<u64>,
   pub path: PathBuf,
+  pub subfolder: Option<PathBuf>,
 }
 
 impl GameScreenshot {
@@ -31,6 +43,37 @@ impl GameScreenshot {
     self.path.file_name().ok_or_else(|| anyhow!("could not determine file name"))
   }
 
+  pub fn media_type(&self) -> Result<MediaType, anyhow::Error> {
+    media::detect_file(&self.path)
+  }
+
+  /// File name to use on remote services, with its extension matching the
+  /// detected content type.
+  pub fn remote_name(&self) -> Result<String, anyhow::Error> {
+    let name = Path::new(self.file_name()?);
+
+    match self.media_type()?.extension {
+      Some(extension) => Ok(name.with_extension(extension).to_string_lossy().into_owned()),
+      None => Ok(name.to_string_lossy().into_owned()),
+    }
+  }
+
+  pub fn steam_id(&self) -> Option<u64> {
+    self.user_id.map(|id| STEAMID64_BASE + id)
+  }
+
+  /// Steam names screenshots after their local capture time, we fall back to
+  /// the file's modification time for anything else.
+  pub fn captured_at(&self) -> Option<DateTime<Local>> {
+    let name = self.file_name().ok()?.to_string_lossy();
+
+    name
+      .get(..14)
+      .and_then(|timestamp| NaiveDateTime::parse_from_str(timestamp, "%Y%m%d%H%M%S").ok())
+      .and_then(|timestamp| Local.from_local_datetime(&timestamp).single())
+      .or_else(|| std::fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok().map(DateTime::<Local>::from))
+  }
+
   pub async fn game_name(&self) -> String {
     if let Ok(name) = APPS.binary_search_by(|(id, _)| id.cmp(&self.game_id)).map(|id| APPS[id].1) {
       return name.to_string();
@@ -50,24 +93,79 @@ impl GameScreenshot {
   }
 
   pub async fn dest_name(&self) -> Result<PathBuf, anyhow::Error> {
-    Ok(Path::new(&self.game_name().await).join(self.file_name()?))
+    let mut dest = PathBuf::from(self.game_name().await);
+
+    if let Some(ref subfolder) = self.subfolder {
+      dest.push(subfolder);
+    }
+
+    Ok(dest.join(self.remote_name()?))
   }
 
-  pub async fn upload(&self, uploader: &dyn Uploader, db: Db) -> Result<&GameScreenshot, anyhow::Error> {
-    match uploader.upload(self).await {
-      Ok(_) => Ok(self),
+  pub async fn upload(&self, destination: &Destination, db: Db, events: &Events, throttle: &Throttle) -> Result<Receipt, anyhow::Error> {
+    match self.try_upload(destination, events, throttle).await {
+      Ok(receipt) => Ok(receipt),
 
-      Err(err) => match self.save(db).await {
+      Err(err) => match self.save(db, &destination.queue()).await {
         Ok(()) => Err(err),
         Err(save_err) => Err(save_err.context(err)),
       },
     }
   }
 
-  pub async fn save(&self, db: Db) -> Result<(), anyhow::Error> {
+  async fn try_upload(&self, destination: &Destination, events: &Events, throttle: &Throttle) -> Result<Receipt, anyhow::Error> {
+    let progress = |stage| {
+      events.emit(Event::Progress {
+        path: self.path.clone(),
+        destination: destination.name.clone(),
+        stage,
+      })
+    };
+
+    progress(Stage::Staging);
+
+    let staged = destination.pipeline.stage(self).await?;
+
+    progress(Stage::Uploading);
+
+    let location = destination.uploader.upload(&staged.screenshot, throttle).await?;
+
+    // The original is already uploaded at this point, retrying the whole
+    // screenshot would send it twice, so a missing thumbnail is only reported.
+    let thumbnail = match staged.thumbnail {
+      Some(ref thumbnail) => {
+        progress(Stage::UploadingThumbnail);
+
+        match destination.uploader.upload(thumbnail, throttle).await {
+          Ok(location) => Some(location),
+
+          Err(err) => {
+            kvlog!(Warn, "could not upload thumbnail", {
+                "error" => format!("{err:#}"),
+                "path" => self.path.display(),
+                "destination" => destination.name
+            });
+
+            None
+          }
+        }
+      }
+
+      None => None,
+    };
+
+    Ok(Receipt {
+      destination: destination.name.clone(),
+      path: self.path.clone(),
+      location,
+      thumbnail,
+    })
+  }
+
+  pub async fn save(&self, db: Db, queue: &str) -> Result<(), anyhow::Error> {
     let mut db = db.lock().await;
 
-    db.ladd("screenshots", &self.path.to_string_lossy()).ok_or_else(|| anyhow!("could not save screenshot"))?;
+    db.ladd(queue, &self.path.to_string_lossy()).ok_or_else(|| anyhow!("could not save screenshot"))?;
 
     Ok(())
   }
@@ -80,7 +178,13 @@ where
   fn from(path: P) -> Self {
     let path = path.as_ref();
     let game_id = path.iter().rev().nth(2).and_then(|id| id.to_string_lossy().parse::<u64>().ok()).unwrap_or(0);
+    let user_id = path.iter().rev().nth(5).and_then(|id| id.to_string_lossy().parse::<u64>().ok());
 
-    GameScreenshot { game_id, path: path.to_owned() }
+    GameScreenshot {
+      game_id,
+      user_id,
+      path: path.to_owned(),
+      subfolder: None,
+    }
   }
 }
@@ -0,0 +1,38 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+/// Directory tree standing in for a system one, such as `/sys` or `/proc`,
+/// removed when dropped.
+pub struct TempTree(PathBuf);
+
+impl TempTree {
+  /// Creates an empty tree, unique to the test and to the process.
+  pub fn new(test: &str) -> TempTree {
+    let root = std::env::temp_dir().join(format!("deckshot-{}-{test}", std::process::id()));
+
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+
+    TempTree(root)
+  }
+
+  pub fn root(&self) -> &Path {
+    &self.0
+  }
+
+  /// Writes a file, creating its parent directories.
+  pub fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) {
+    let path = self.0.join(path);
+
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, contents).unwrap();
+  }
+}
+
+impl Drop for TempTree {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.0);
+  }
+}
@@ -0,0 +1,123 @@
+use anyhow::anyhow;
+use exif::{experimental::Writer, Field, In, Tag, Value};
+use img_parts::{
+  jpeg::{markers, Jpeg, JpegSegment},
+  Bytes, ImageEXIF,
+};
+use serde::Deserialize;
+
+use crate::{transforms::Transform, GameScreenshot};
+
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+#[derive(Clone, Default, Deserialize)]
+pub struct MetadataConfig {
+  #[serde(default)]
+  pub enabled: bool,
+  pub device_name: Option<String>,
+}
+
+/// Writes the game name, Steam app ID, capture time, Steam user and device name
+/// into the EXIF and XMP segments of JPEG screenshots. Existing EXIF data is
+/// replaced, since Steam does not write any.
+pub struct MetadataTransform {
+  device_name: String,
+}
+
+impl MetadataTransform {
+  pub fn build(config: &MetadataConfig) -> Result<Self, anyhow::Error> {
+    let device_name = match config.device_name {
+      Some(ref name) => name.clone(),
+      None => std::fs::read_to_string("/etc/hostname").map(|name| name.trim().to_string()).unwrap_or_else(|_| "steamdeck".to_string()),
+    };
+
+    Ok(MetadataTransform { device_name })
+  }
+
+  fn exif(&self, screenshot: &GameScreenshot, game: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let ascii = |tag: Tag, value: String| Field {
+      tag,
+      ifd_num: In::PRIMARY,
+      value: Value::Ascii(vec![value.into_bytes()]),
+    };
+
+    let mut fields = vec![
+      ascii(Tag::ImageDescription, game.to_string()),
+      ascii(Tag::Model, self.device_name.clone()),
+      ascii(Tag::Software, "deckshot".to_string()),
+      ascii(Tag::ImageUniqueID, format!("steam:{}", screenshot.game_id)),
+    ];
+
+    if let Some(steam_id) = screenshot.steam_id() {
+      fields.push(ascii(Tag::Artist, steam_id.to_string()));
+    }
+
+    if let Some(captured_at) = screenshot.captured_at() {
+      fields.push(ascii(Tag::DateTimeOriginal, captured_at.format("%Y:%m:%d %H:%M:%S").to_string()));
+    }
+
+    let mut writer = Writer::new();
+    let mut buffer = std::io::Cursor::new(Vec::new());
+
+    for field in fields.iter() {
+      writer.push_field(field);
+    }
+
+    writer.write(&mut buffer, false)?;
+
+    Ok(buffer.into_inner())
+  }
+
+  fn xmp(&self, screenshot: &GameScreenshot, game: &str) -> String {
+    let mut properties = vec![
+      format!("<dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>", escape(game)),
+      format!("<tiff:Model>{}</tiff:Model>", escape(&self.device_name)),
+      format!("<deckshot:AppId>{}</deckshot:AppId>", screenshot.game_id),
+    ];
+
+    if let Some(steam_id) = screenshot.steam_id() {
+      properties.push(format!("<deckshot:SteamId>{steam_id}</deckshot:SteamId>"));
+    }
+
+    if let Some(captured_at) = screenshot.captured_at() {
+      properties.push(format!("<xmp:CreateDate>{}</xmp:CreateDate>", captured_at.to_rfc3339()));
+    }
+
+    format!(
+      concat!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+        "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+        "<rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\" xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" ",
+        "xmlns:tiff=\"http://ns.adobe.com/tiff/1.0/\" xmlns:deckshot=\"https://github.com/apognu/deckshot/ns/1.0/\">",
+        "{}",
+        "</rdf:Description></rdf:RDF></x:xmpmeta>",
+        "<?xpacket end=\"w\"?>"
+      ),
+      properties.join("")
+    )
+  }
+}
+
+impl Transform for MetadataTransform {
+  fn name(&self) -> &'static str {
+    "metadata"
+  }
+
+  fn apply(&self, screenshot: &GameScreenshot, game: &str, image: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
+    let mut jpeg = Jpeg::from_bytes(image.into()).map_err(|_| anyhow!("metadata can only be written to JPEG files"))?;
+
+    jpeg.set_exif(Some(self.exif(screenshot, game)?.into()));
+
+    let xmp = [XMP_HEADER, self.xmp(screenshot, game).as_bytes()].concat();
+    let segments = jpeg.segments_mut();
+
+    segments.retain(|segment| !(segment.marker() == markers::APP1 && segment.contents().starts_with(XMP_HEADER)));
+    segments.insert(segments.len().min(4), JpegSegment::new_with_contents(markers::APP1, Bytes::from(xmp)));
+
+    Ok(jpeg.encoder().bytes().to_vec())
+  }
+}
+
+fn escape(value: &str) -> String {
+  value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
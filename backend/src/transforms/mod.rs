@@ -0,0 +1,144 @@
+pub mod metadata;
+pub mod overlay;
+pub mod redact;
+pub mod thumbnail;
+
+use std::{io::Cursor, path::PathBuf};
+
+use anyhow::Context;
+use image::{DynamicImage, ImageFormat, ImageOutputFormat, RgbaImage};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use tokio::fs;
+
+use crate::{
+  config::{Config, DestinationConfig},
+  GameScreenshot,
+};
+
+use self::{metadata::MetadataTransform, overlay::OverlayTransform, redact::RedactTransform, thumbnail::Thumbnailer};
+
+pub trait Transform: Sync + Send {
+  fn name(&self) -> &'static str;
+  fn apply(&self, screenshot: &GameScreenshot, game: &str, image: Vec<u8>) -> Result<Vec<u8>, anyhow::Error>;
+}
+
+pub struct Pipeline {
+  staging_path: PathBuf,
+  transforms: Vec<Box<dyn Transform>>,
+  thumbnailer: Option<Thumbnailer>,
+}
+
+/// Screenshot handed to an uploader, either the original file or a transformed
+/// copy living in the staging directory until it is dropped, along with its
+/// thumbnail if enabled.
+pub struct Staged {
+  dir: Option<PathBuf>,
+  pub screenshot: GameScreenshot,
+  pub thumbnail: Option<GameScreenshot>,
+}
+
+impl Pipeline {
+  pub fn build(config: &Config, destination: &DestinationConfig) -> Result<Self, anyhow::Error> {
+    let mut transforms: Vec<Box<dyn Transform>> = Vec::new();
+
+    if destination.redact {
+      transforms.push(Box::new(RedactTransform::build(&config.redaction)?));
+    }
+
+    if let Some(ref overlay) = destination.overlay {
+      transforms.push(Box::new(OverlayTransform::build(overlay, destination.uploader.username())?));
+    }
+
+    // Metadata is written last, since transforms re-encoding the image would
+    // drop it.
+    if config.metadata.enabled {
+      transforms.push(Box::new(MetadataTransform::build(&config.metadata)?));
+    }
+
+    let thumbnailer = match destination.thumbnail {
+      Some(ref thumbnail) => Some(Thumbnailer::build(thumbnail)?),
+      None => None,
+    };
+
+    Ok(Pipeline {
+      staging_path: config.deckshot_path.join("staging"),
+      transforms,
+      thumbnailer,
+    })
+  }
+
+  /// Returns whether staging a screenshot involves image processing.
+  pub fn processes(&self) -> bool {
+    !self.transforms.is_empty() || self.thumbnailer.is_some()
+  }
+
+  pub async fn stage(&self, screenshot: &GameScreenshot) -> Result<Staged, anyhow::Error> {
+    if !self.processes() {
+      return Ok(Staged {
+        dir: None,
+        screenshot: screenshot.clone(),
+        thumbnail: None,
+      });
+    }
+
+    let id: String = thread_rng().sample_iter(&Alphanumeric).take(16).map(char::from).collect();
+    let dir = self.staging_path.join(id);
+
+    fs::create_dir_all(&dir).await.context("could not create staging directory")?;
+
+    let mut staged = Staged {
+      dir: Some(dir.clone()),
+      screenshot: screenshot.clone(),
+      thumbnail: None,
+    };
+
+    let mut image = fs::read(&screenshot.path).await.context("could not read screenshot")?;
+
+    if !self.transforms.is_empty() {
+      let game = screenshot.game_name().await;
+
+      for transform in self.transforms.iter() {
+        image = transform.apply(screenshot, &game, image).context(format!("could not apply transform: {}", transform.name()))?;
+      }
+
+      staged.screenshot.path = dir.join(screenshot.file_name()?);
+
+      fs::write(&staged.screenshot.path, &image).await.context("could not write staged screenshot")?;
+    }
+
+    if let Some(ref thumbnailer) = self.thumbnailer {
+      let thumbnail = GameScreenshot {
+        path: dir.join(thumbnailer.file_name(&screenshot.path)),
+        subfolder: thumbnailer.folder(),
+        ..screenshot.clone()
+      };
+
+      fs::write(&thumbnail.path, thumbnailer.generate(&image).context("could not generate thumbnail")?)
+        .await
+        .context("could not write staged thumbnail")?;
+
+      staged.thumbnail = Some(thumbnail);
+    }
+
+    Ok(staged)
+  }
+}
+
+impl Drop for Staged {
+  fn drop(&mut self) {
+    if let Some(ref dir) = self.dir {
+      let _ = std::fs::remove_dir_all(dir);
+    }
+  }
+}
+
+pub(crate) fn encode(canvas: RgbaImage, format: ImageFormat) -> Result<Vec<u8>, anyhow::Error> {
+  let mut buffer = Cursor::new(Vec::new());
+
+  match format {
+    ImageFormat::Jpeg => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).into_rgb8()).write_to(&mut buffer, ImageOutputFormat::Jpeg(90))?,
+    format => canvas.write_to(&mut buffer, format)?,
+  }
+
+  Ok(buffer.into_inner())
+}
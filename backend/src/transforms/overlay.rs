@@ -0,0 +1,153 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use image::{Rgba, RgbaImage};
+use imageproc::drawing::{draw_text_mut, text_size};
+use rusttype::{Font, Scale};
+use serde::Deserialize;
+
+use crate::{
+  transforms::{encode, Transform},
+  GameScreenshot,
+};
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Corner {
+  TopLeft,
+  TopRight,
+  BottomLeft,
+  #[default]
+  BottomRight,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct OverlayConfig {
+  #[serde(default = "default_text")]
+  pub text: String,
+  pub username: Option<String>,
+  #[serde(default)]
+  pub position: Corner,
+  #[serde(default = "default_opacity")]
+  pub opacity: f32,
+  #[serde(default = "default_font_size")]
+  pub font_size: f32,
+  /// Path of a TrueType font, the first of `DEFAULT_FONTS` installed if not
+  /// set.
+  pub font: Option<String>,
+  #[serde(default = "default_date_format")]
+  pub date_format: String,
+}
+
+fn default_text() -> String {
+  "{game} - {date}".to_string()
+}
+
+const fn default_opacity() -> f32 {
+  0.8
+}
+
+const fn default_font_size() -> f32 {
+  24.0
+}
+
+fn default_date_format() -> String {
+  "%Y-%m-%d %H:%M".to_string()
+}
+
+/// Locations of DejaVu Sans on SteamOS and Arch, Debian and Ubuntu, and
+/// Fedora.
+pub const DEFAULT_FONTS: &[&str] = &[
+  "/usr/share/fonts/TTF/DejaVuSans.ttf",
+  "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+  "/usr/share/fonts/dejavu-sans-fonts/DejaVuSans.ttf",
+];
+
+const MARGIN: i32 = 16;
+const COLOR: [u8; 3] = [255, 255, 255];
+
+impl OverlayConfig {
+  /// Returns the configured font, or the first default one installed.
+  pub fn font(&self) -> Option<String> {
+    self.font.clone().or_else(|| DEFAULT_FONTS.iter().find(|font| Path::new(font).is_file()).map(|font| font.to_string()))
+  }
+}
+
+/// Draws a caption built from a text template onto the screenshot. The
+/// template can use the `{game}`, `{appid}`, `{date}` and `{username}`
+/// placeholders.
+pub struct OverlayTransform {
+  config: OverlayConfig,
+  username: Option<String>,
+  font: Font<'static>,
+}
+
+impl OverlayTransform {
+  pub fn build(config: &OverlayConfig, username: Option<String>) -> Result<Self, anyhow::Error> {
+    let path = config.font().ok_or_else(|| anyhow!("no default overlay font installed, set 'font' to the path of a TrueType font"))?;
+    let data = std::fs::read(&path).context(format!("could not read overlay font: {path}"))?;
+    let font = Font::try_from_vec(data).ok_or_else(|| anyhow!("could not parse overlay font: {path}"))?;
+
+    Ok(OverlayTransform {
+      config: config.clone(),
+      username: config.username.clone().or(username),
+      font,
+    })
+  }
+
+  fn caption(&self, screenshot: &GameScreenshot, game: &str) -> String {
+    let date = screenshot.captured_at().map(|date| date.format(&self.config.date_format).to_string()).unwrap_or_default();
+
+    self
+      .config
+      .text
+      .replace("{game}", game)
+      .replace("{appid}", &screenshot.game_id.to_string())
+      .replace("{date}", &date)
+      .replace("{username}", self.username.as_deref().unwrap_or_default())
+  }
+}
+
+impl Transform for OverlayTransform {
+  fn name(&self) -> &'static str {
+    "overlay"
+  }
+
+  fn apply(&self, screenshot: &GameScreenshot, game: &str, image: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
+    let format = image::guess_format(&image)?;
+    let mut canvas = image::load_from_memory_with_format(&image, format)?.into_rgba8();
+
+    let caption = self.caption(screenshot, game);
+    let scale = Scale::uniform(self.config.font_size);
+    let (width, height) = text_size(scale, &self.font, &caption);
+
+    let x = match self.config.position {
+      Corner::TopLeft | Corner::BottomLeft => MARGIN,
+      Corner::TopRight | Corner::BottomRight => canvas.width() as i32 - width - MARGIN,
+    };
+
+    let y = match self.config.position {
+      Corner::TopLeft | Corner::TopRight => MARGIN,
+      Corner::BottomLeft | Corner::BottomRight => canvas.height() as i32 - height - MARGIN,
+    };
+
+    // Text is rendered on a transparent layer so its coverage can be used as
+    // an alpha mask, scaled by the configured opacity.
+    let mut layer = RgbaImage::new(canvas.width(), canvas.height());
+    draw_text_mut(&mut layer, Rgba([255, 255, 255, 255]), x, y, scale, &self.font, &caption);
+
+    let opacity = self.config.opacity.clamp(0.0, 1.0);
+
+    for (pixel, mask) in canvas.pixels_mut().zip(layer.pixels()) {
+      let alpha = mask[3] as f32 / 255.0 * opacity;
+
+      if alpha > 0.0 {
+        for (value, color) in pixel.0.iter_mut().zip(COLOR) {
+          *value = (*value as f32 * (1.0 - alpha) + color as f32 * alpha).round() as u8;
+        }
+      }
+    }
+
+    encode(canvas, format)
+  }
+}
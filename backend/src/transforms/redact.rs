@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use image::{imageops, Rgba};
+use serde::Deserialize;
+
+use crate::{
+  transforms::{encode, Transform},
+  GameScreenshot,
+};
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactionMode {
+  #[default]
+  Blur,
+  Black,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Region {
+  pub x: u32,
+  pub y: u32,
+  pub width: u32,
+  pub height: u32,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct RedactionConfig {
+  #[serde(default)]
+  pub mode: RedactionMode,
+  #[serde(default)]
+  pub regions: HashMap<u64, Vec<Region>>,
+}
+
+/// Blurs or blacks out the regions configured for the screenshot's game,
+/// screenshots from other games are passed through untouched.
+pub struct RedactTransform {
+  config: RedactionConfig,
+}
+
+impl RedactTransform {
+  pub fn build(config: &RedactionConfig) -> Result<Self, anyhow::Error> {
+    Ok(RedactTransform { config: config.clone() })
+  }
+}
+
+impl Transform for RedactTransform {
+  fn name(&self) -> &'static str {
+    "redact"
+  }
+
+  fn apply(&self, screenshot: &GameScreenshot, _game: &str, image: Vec<u8>) -> Result<Vec<u8>, anyhow::Error> {
+    let regions = match self.config.regions.get(&screenshot.game_id) {
+      Some(regions) if !regions.is_empty() => regions,
+      _ => return Ok(image),
+    };
+
+    let format = image::guess_format(&image)?;
+    let mut canvas = image::load_from_memory_with_format(&image, format)?.into_rgba8();
+
+    for region in regions {
+      let x = region.x.min(canvas.width());
+      let y = region.y.min(canvas.height());
+      let width = region.width.min(canvas.width() - x);
+      let height = region.height.min(canvas.height() - y);
+
+      if width == 0 || height == 0 {
+        continue;
+      }
+
+      match self.config.mode {
+        RedactionMode::Black => {
+          for py in y..y + height {
+            for px in x..x + width {
+              canvas.put_pixel(px, py, Rgba([0, 0, 0, 255]));
+            }
+          }
+        }
+
+        RedactionMode::Blur => {
+          let area = imageops::crop_imm(&canvas, x, y, width, height).to_image();
+          let sigma = (width.min(height) as f32 / 4.0).max(8.0);
+
+          imageops::replace(&mut canvas, &imageops::blur(&area, sigma), x as i64, y as i64);
+        }
+      }
+    }
+
+    encode(canvas, format)
+  }
+}
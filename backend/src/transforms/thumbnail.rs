@@ -0,0 +1,54 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::transforms::encode;
+
+#[derive(Clone, Deserialize)]
+pub struct ThumbnailConfig {
+  #[serde(default = "default_size")]
+  pub size: u32,
+  #[serde(default = "default_suffix")]
+  pub suffix: String,
+  pub folder: Option<PathBuf>,
+}
+
+const fn default_size() -> u32 {
+  320
+}
+
+fn default_suffix() -> String {
+  "_thumb".to_string()
+}
+
+/// Generates resized previews of screenshots, fitting within a square of the
+/// configured size while preserving the aspect ratio.
+pub struct Thumbnailer {
+  config: ThumbnailConfig,
+}
+
+impl Thumbnailer {
+  pub fn build(config: &ThumbnailConfig) -> Result<Self, anyhow::Error> {
+    Ok(Thumbnailer { config: config.clone() })
+  }
+
+  pub fn folder(&self) -> Option<PathBuf> {
+    self.config.folder.clone()
+  }
+
+  pub fn file_name(&self, original: &Path) -> PathBuf {
+    let stem = original.file_stem().unwrap_or_default().to_string_lossy();
+
+    match original.extension() {
+      Some(extension) => PathBuf::from(format!("{stem}{}.{}", self.config.suffix, extension.to_string_lossy())),
+      None => PathBuf::from(format!("{stem}{}", self.config.suffix)),
+    }
+  }
+
+  pub fn generate(&self, image: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let format = image::guess_format(image)?;
+    let thumbnail = image::load_from_memory_with_format(image, format)?.thumbnail(self.config.size, self.config.size);
+
+    encode(thumbnail.into_rgba8(), format)
+  }
+}
@@ -1,28 +1,41 @@
+use anyhow::anyhow;
+use reqwest::{
+  header::AUTHORIZATION,
+  multipart::{Form, Part},
+  Body,
+};
 use serde::Deserialize;
-use serenity::{self, async_trait, model::prelude::ChannelId, prelude::*};
+use serde_json::json;
+use serenity::{
+  self, async_trait,
+  http::{routing::Route, Http},
+  model::prelude::{ChannelId, Message},
+};
+use tokio::fs;
 
-use crate::{config::Config, GameScreenshot, Uploader};
+use crate::{bandwidth::Throttle, uploaders::Health, GameScreenshot, Uploader};
 
 pub struct DiscordUploader {
-  client: Client,
+  name: String,
+  http: Http,
   channel: u64,
   username: Option<String>,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct DiscordConfig {
-  token: String,
-  channel: u64,
-  username: Option<String>,
+  pub token: String,
+  pub channel: u64,
+  pub username: Option<String>,
 }
 
 impl DiscordUploader {
-  pub async fn build(_deckshot: &Config, config: DiscordConfig) -> Result<Self, anyhow::Error> {
-    let intents = GatewayIntents::non_privileged() | GatewayIntents::MESSAGE_CONTENT;
-    let client = Client::builder(config.token.clone(), intents).await.expect("Error creating client");
-
+  /// Only the REST API is used, which does not require connecting to the
+  /// gateway.
+  pub fn build(name: &str, config: DiscordConfig) -> Result<Self, anyhow::Error> {
     Ok(DiscordUploader {
-      client,
+      name: name.to_string(),
+      http: Http::new(&config.token),
       channel: config.channel,
       username: config.username,
     })
@@ -35,19 +48,57 @@ impl Uploader for DiscordUploader {
     "Discord"
   }
 
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
-    let game_name = screenshot.game_name().await;
+  fn endpoint(&self) -> Option<&str> {
+    Some("https://discord.com")
+  }
 
-    let http = &self.client.cache_and_http.http;
-    let channel = ChannelId(self.channel);
+  async fn upload(&self, screenshot: &GameScreenshot, throttle: &Throttle) -> Result<String, anyhow::Error> {
+    let game_name = screenshot.game_name().await;
 
     let text = match self.username {
       Some(ref username) => format!("{username} took a new screenshot from {game_name}"),
       None => format!("New screenshot from {game_name}"),
     };
 
-    channel.send_message(http, |message| message.content(text).add_file(screenshot.path.as_path())).await?;
+    let data = fs::read(&screenshot.path).await?;
+    let length = data.len() as u64;
+
+    // The attachment is streamed so its chunks follow the throttle, which the
+    // client of serenity does not allow.
+    let attachment = Part::stream_with_length(Body::wrap_stream(throttle.stream(data)), length)
+      .file_name(screenshot.remote_name()?)
+      .mime_str(screenshot.media_type()?.mime)?;
+
+    let form = Form::new().text("payload_json", json!({ "content": text }).to_string()).part("files[0]", attachment);
+
+    let response = reqwest::Client::new()
+      .post(Route::channel_messages(self.channel, None))
+      .header(AUTHORIZATION, &self.http.token)
+      .multipart(form)
+      .send()
+      .await?;
+
+    let message = match response.status().as_u16() {
+      200 => response.json::<Message>().await?,
+      code @ (401 | 403) => return Err(anyhow!("upload responded with code {code}, the bot token or its access to the channel was rejected")),
+      code => return Err(anyhow!(format!("upload responded with code {code}"))),
+    };
+
+    Ok(message.attachments.first().map(|attachment| attachment.url.clone()).unwrap_or_else(|| message.link()))
+  }
+
+  async fn check(&self) -> Result<Health, anyhow::Error> {
+    match ChannelId(self.channel).to_channel(&self.http).await {
+      Ok(_) => Ok(Health::Valid),
+
+      // Bot tokens are not obtained through 'deckshot auth', they are not
+      // reported as an `AuthError`.
+      Err(serenity::Error::Http(err)) if matches!(err.status_code().map(|status| status.as_u16()), Some(401 | 403)) => Ok(Health::Invalid {
+        reason: format!("the bot token or its access to the channel was rejected: {err}"),
+        hint: format!("update the bot token of '{}' in deckshot.yml, or the secret it references with 'deckshot secret'", self.name),
+      }),
 
-    Ok(screenshot)
+      Err(err) => Err(err.into()),
+    }
   }
 }
@@ -1,50 +1,158 @@
+use std::sync::Arc;
+
 use dropbox_sdk::{
-  default_client::{NoauthDefaultClient, UserAuthDefaultClient},
-  files::{self, UploadArg},
-  oauth2::{Authorization, AuthorizeUrlBuilder, Oauth2Type, PkceCode},
+  default_client::UserAuthDefaultClient,
+  files::{self, CommitInfo, FileMetadata, UploadArg, UploadSessionAppendArg, UploadSessionCursor, UploadSessionFinishArg, UploadSessionStartArg},
+  oauth2::Authorization,
+  users,
 };
+use oauth2::{basic::BasicClient, reqwest::async_http_client, AuthType, AuthUrl, AuthorizationCode, ClientId, CsrfToken, PkceCodeChallenge, RedirectUrl, TokenUrl};
 use serde::Deserialize;
 use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::{
-  config::Config,
-  database::{load_token, save_token},
-  uploaders::prompt_authorization_code,
+  bandwidth::Throttle,
+  secrets::SecretStore,
+  uploaders::{
+    oauth,
+    tokens::{self, AuthError, Credential, Refresher, TokenStore},
+    Health,
+  },
   GameScreenshot, Uploader,
 };
 
+/// Size of the parts of an upload session, which Dropbox wants in multiples of
+/// 4 MiB.
+const PART_SIZE: usize = 4 * 1024 * 1024;
+
 pub struct DropboxUploader {
-  config: Config,
-  client_id: String,
+  tokens: TokenStore,
+  client: BasicClient,
+  redirect_uri: Option<String>,
   folder: Option<String>,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct DropboxConfig {
   pub client_id: String,
+  pub redirect_uri: Option<String>,
   pub folder: Option<String>,
 }
 
 impl DropboxUploader {
-  pub async fn build(deckshot: &Config, config: DropboxConfig) -> Result<Self, anyhow::Error> {
+  pub async fn build(secrets: &Arc<SecretStore>, name: &str, config: DropboxConfig) -> Result<Self, anyhow::Error> {
+    let mut client = BasicClient::new(
+      ClientId::new(config.client_id),
+      None,
+      AuthUrl::new("https://www.dropbox.com/oauth2/authorize".to_string())?,
+      Some(TokenUrl::new("https://api.dropboxapi.com/oauth2/token".to_string())?),
+    )
+    .set_auth_type(AuthType::RequestBody);
+
+    if let Some(ref redirect_uri) = config.redirect_uri {
+      client = client.set_redirect_uri(RedirectUrl::new(redirect_uri.clone())?);
+    }
+
     Ok(DropboxUploader {
-      config: deckshot.clone(),
-      client_id: config.client_id.clone(),
+      tokens: TokenStore::new(secrets, name).with_import(import_authorization),
+      client,
+      redirect_uri: config.redirect_uri,
       folder: config.folder,
     })
   }
 }
 
+impl DropboxUploader {
+  /// Turns authentication failures into an `AuthError`.
+  fn unauthorized<T>(&self, result: Result<T, dropbox_sdk::Error>) -> Result<T, anyhow::Error> {
+    match result {
+      Ok(value) => Ok(value),
+      Err(dropbox_sdk::Error::Authentication(err)) => Err(AuthError::new(self.tokens.key(), err).into()),
+      Err(err) => Err(err.into()),
+    }
+  }
+
+  /// Sends the file one part at a time, as bandwidth allows.
+  async fn upload_session(&self, client: &Arc<UserAuthDefaultClient>, buffer: Vec<u8>, dest: &str, throttle: &Throttle) -> Result<FileMetadata, anyhow::Error> {
+    let buffer = Arc::new(buffer);
+    let session = self.unauthorized(blocking(client, |client| files::upload_session_start(client, &UploadSessionStartArg::default(), &[])).await?)??;
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+      let end = (offset + PART_SIZE).min(buffer.len());
+
+      throttle.acquire(end - offset).await;
+
+      let append = UploadSessionAppendArg::new(UploadSessionCursor::new(session.session_id.clone(), offset as u64));
+      let data = buffer.clone();
+
+      self.unauthorized(blocking(client, move |client| files::upload_session_append_v2(client, &append, &data[offset..end])).await?)??;
+
+      offset = end;
+    }
+
+    let finish = UploadSessionFinishArg::new(UploadSessionCursor::new(session.session_id, offset as u64), CommitInfo::new(dest.to_string()));
+
+    Ok(self.unauthorized(blocking(client, move |client| files::upload_session_finish(client, &finish, &[])).await?)??)
+  }
+}
+
+/// Runs a call of the Dropbox client, which blocks on its requests, away from
+/// the threads of the runtime.
+async fn blocking<T, F>(client: &Arc<UserAuthDefaultClient>, call: F) -> Result<T, anyhow::Error>
+where
+  F: FnOnce(&UserAuthDefaultClient) -> T + Send + 'static,
+  T: Send + 'static,
+{
+  let client = client.clone();
+
+  Ok(tokio::task::spawn_blocking(move || call(&client)).await?)
+}
+
+/// Previous versions saved the `Authorization` of the Dropbox SDK, as `1&` and
+/// a long-lived access token, or `2&` and a refresh token.
+fn import_authorization(secrets: &SecretStore, key: &str) -> Option<(Credential, Vec<String>)> {
+  let saved = secrets.load(key).ok()?;
+
+  let credential = match saved.split_once('&')? {
+    ("1", access_token) => Credential {
+      access_token: access_token.to_string(),
+      ..Default::default()
+    },
+
+    // There is no access token yet, so it is obtained on first use.
+    ("2", refresh_token) => Credential {
+      refresh_token: Some(refresh_token.to_string()),
+      expires_at: Some(0),
+      ..Default::default()
+    },
+
+    _ => return None,
+  };
+
+  Some((credential, vec![key.to_string()]))
+}
+
+#[async_trait]
+impl Refresher for DropboxUploader {
+  async fn refresh(&self, credential: &Credential) -> Result<Credential, anyhow::Error> {
+    tokens::refresh_oauth(&self.client, self.tokens.key(), credential).await
+  }
+}
+
 #[async_trait]
 impl Uploader for DropboxUploader {
   fn name(&self) -> &'static str {
     "Dropbox"
   }
 
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
-    let token = load_token(&self.config, "dropbox").await?;
-    let auth = Authorization::load(self.client_id.clone(), &token).unwrap();
-    let client = UserAuthDefaultClient::new(auth);
+  fn endpoint(&self) -> Option<&str> {
+    Some("https://content.dropboxapi.com")
+  }
+
+  async fn upload(&self, screenshot: &GameScreenshot, throttle: &Throttle) -> Result<String, anyhow::Error> {
+    let token = self.tokens.access_token(self).await?;
+    let client = Arc::new(UserAuthDefaultClient::new(Authorization::from_long_lived_access_token(token)));
 
     let dest = if let Some(ref folder) = self.folder {
       format!("/{}/{}", folder, screenshot.dest_name().await?.to_string_lossy())
@@ -57,23 +165,53 @@ impl Uploader for DropboxUploader {
 
     file.read_to_end(&mut buffer).await?;
 
-    let args = UploadArg::new(dest);
+    // The client sends every request body in one piece, so the file only
+    // goes through an upload session when bandwidth is limited.
+    let metadata = if throttle.is_limited() && !buffer.is_empty() {
+      self.upload_session(&client, buffer, &dest, throttle).await?
+    } else {
+      let upload = UploadArg::new(dest.clone());
 
-    files::upload(&client, &args, &buffer)??;
+      self.unauthorized(blocking(&client, move |client| files::upload(client, &upload, &buffer)).await?)??
+    };
 
-    Ok(screenshot)
+    Ok(metadata.path_display.unwrap_or(dest))
   }
 
+  async fn check(&self) -> Result<Health, anyhow::Error> {
+    let token = self.tokens.access_token(self).await?;
+    let client = Arc::new(UserAuthDefaultClient::new(Authorization::from_long_lived_access_token(token)));
+
+    self.unauthorized(blocking(&client, users::get_current_account).await?)??;
+
+    Ok(self.tokens.health().await)
+  }
+
+  /// Requests offline access, so that Dropbox issues a refresh token along
+  /// with its short-lived access tokens.
   async fn auth(&self) -> Result<(), anyhow::Error> {
-    let pkce = PkceCode::new();
-    let flow = Oauth2Type::PKCE(pkce);
-    let url = AuthorizeUrlBuilder::new(&self.client_id, &flow).build();
-    let code = prompt_authorization_code(url.as_str())?;
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let (url, state) = self
+      .client
+      .authorize_url(CsrfToken::new_random)
+      .set_pkce_challenge(challenge)
+      .add_extra_param("token_access_type", "offline")
+      .url();
+
+    let code = match self.redirect_uri {
+      Some(ref redirect_uri) => oauth::authorization_code(url.as_str(), redirect_uri, state.secret()).await?,
+      None => oauth::prompt_authorization_code(url.as_str())?,
+    };
 
-    let mut auth = Authorization::from_auth_code(self.client_id.clone(), flow, code, None);
-    auth.obtain_access_token(NoauthDefaultClient::default())?;
+    let tokens = self
+      .client
+      .exchange_code(AuthorizationCode::new(code))
+      .set_pkce_verifier(verifier)
+      .request_async(async_http_client)
+      .await?;
 
-    save_token(&self.config, "dropbox", &auth.save().unwrap()).await?;
+    self.tokens.save(Credential::from(&tokens)).await?;
 
     Ok(())
   }
@@ -1,81 +1,320 @@
-use std::fs::File;
+use std::{error::Error, fs::File, future::Future, pin::Pin, sync::Arc};
 
-use anyhow::Context;
+use ::oauth2::{basic::BasicClient, AuthType, AuthUrl, ClientId, ClientSecret, TokenUrl};
+use anyhow::{anyhow, Context};
 use google_drive3::{
   api::{DriveHub, File as RemoteFile},
-  client::oauth2 as helpers,
+  client::{oauth2 as helpers, GetToken},
   hyper::{self, client::HttpConnector},
   hyper_rustls::{self, HttpsConnector},
-  oauth2,
+  oauth2::{
+    self,
+    authenticator_delegate::{DeviceAuthResponse, DeviceFlowDelegate},
+    storage::{TokenInfo, TokenStorage},
+    ApplicationSecret, InstalledFlowReturnMethod,
+  },
 };
 use serde::Deserialize;
 
-use crate::{GameScreenshot, Uploader};
+use crate::{
+  bandwidth::Throttle,
+  secrets::SecretStore,
+  uploaders::{
+    oauth::{self as flows, AuthFlow},
+    tokens::{self, Credential, Refresher, TokenStore},
+    Health,
+  },
+  GameScreenshot, Uploader,
+};
+
+const SCOPE_FULL: &str = "https://www.googleapis.com/auth/drive";
+const SCOPE_FILE: &str = "https://www.googleapis.com/auth/drive.file";
+const DEFAULT_FOLDER: &str = "Deckshot";
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
 
 pub struct GoogleDriveUploader {
   hub: DriveHub<HttpsConnector<HttpConnector>>,
-  folder: String,
+  scope: &'static str,
+  /// User credentials, with OAuth.
+  user: Option<Arc<UserTokens>>,
+  flow: AuthFlow,
+  folder: Option<String>,
+}
+
+/// Tokens of the user deckshot uploads as, along with the client they were
+/// issued to.
+struct UserTokens {
+  tokens: TokenStore,
+  secret: ApplicationSecret,
+  client: BasicClient,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GoogleDriveAuth {
+  #[default]
+  ServiceAccount,
+  Oauth,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct GoogleDriveConfig {
-  pub private_key_file: String,
-  pub folder: String,
+  #[serde(default)]
+  pub auth: GoogleDriveAuth,
+  pub private_key_file: Option<String>,
+  pub client_secret_file: Option<String>,
+  #[serde(default)]
+  pub flow: AuthFlow,
+  pub folder: Option<String>,
 }
 
 impl GoogleDriveUploader {
-  pub async fn build(config: GoogleDriveConfig) -> Result<Self, anyhow::Error> {
-    let pkey = helpers::read_service_account_key(config.private_key_file).await?;
-    let auth = oauth2::ServiceAccountAuthenticator::builder(pkey).build().await.context("could not parse private key")?;
+  pub async fn build(secrets: &Arc<SecretStore>, name: &str, config: GoogleDriveConfig) -> Result<Self, anyhow::Error> {
+    let (hub, scope, user) = match config.auth {
+      GoogleDriveAuth::ServiceAccount => {
+        let private_key_file = config.private_key_file.as_ref().ok_or_else(|| anyhow!("'private_key_file' is required with service accounts"))?;
 
-    let hub = DriveHub::new(
-      hyper::Client::builder().build(hyper_rustls::HttpsConnectorBuilder::new().with_native_roots().https_or_http().enable_http1().enable_http2().build()),
-      auth,
-    );
+        if config.folder.is_none() {
+          return Err(anyhow!("'folder' is required with service accounts"));
+        }
 
-    Ok(GoogleDriveUploader { hub, folder: config.folder })
-  }
-}
+        let pkey = helpers::read_service_account_key(private_key_file).await?;
+        let auth = oauth2::ServiceAccountAuthenticator::builder(pkey).build().await.context("could not parse private key")?;
 
-#[async_trait]
-impl Uploader for GoogleDriveUploader {
-  fn name(&self) -> &'static str {
-    "Google Drive"
+        (DriveHub::new(https_client(), auth), SCOPE_FULL, None)
+      }
+
+      // Tokens obtained through either flow are kept in the token store, and
+      // refreshed from there. The interactive flows only run from `deckshot
+      // auth`, the service cannot wait for a user to sign in.
+      GoogleDriveAuth::Oauth => {
+        let secret = read_client_secret(&config.client_secret_file).await?;
+
+        let client = BasicClient::new(
+          ClientId::new(secret.client_id.clone()),
+          Some(ClientSecret::new(secret.client_secret.clone())),
+          AuthUrl::new(secret.auth_uri.clone()).context("invalid authorization URL in client secret")?,
+          Some(TokenUrl::new(secret.token_uri.clone()).context("invalid token URL in client secret")?),
+        )
+        .set_auth_type(AuthType::RequestBody);
+
+        let user = Arc::new(UserTokens {
+          tokens: TokenStore::new(secrets, name),
+          secret,
+          client,
+        });
+
+        (DriveHub::new(https_client(), StoredTokens(user.clone())), SCOPE_FILE, Some(user))
+      }
+    };
+
+    Ok(GoogleDriveUploader {
+      hub,
+      scope,
+      user,
+      flow: config.flow,
+      folder: config.folder,
+    })
   }
 
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
-    let filename = screenshot.file_name()?;
-    let game = screenshot.game_name().await;
+  /// Renews user credentials ahead of the API calls, so that rejected ones are
+  /// reported as an `AuthError` rather than a failed request.
+  async fn ensure_tokens(&self) -> Result<(), anyhow::Error> {
+    if let Some(ref user) = self.user {
+      user.tokens.access_token(&**user).await?;
+    }
+
+    Ok(())
+  }
 
+  async fn find_or_create_folder(&self, name: &str, parent: &str) -> Result<String, anyhow::Error> {
     let (_, folders) = self
       .hub
       .files()
       .list()
-      .q(&format!("mimeType = 'application/vnd.google-apps.folder' and '{}' in parents and name = '{game}'", self.folder))
+      .q(&format!("mimeType = '{FOLDER_MIME_TYPE}' and '{parent}' in parents and name = '{name}' and trashed = false"))
+      .add_scope(self.scope)
       .doit()
       .await
-      .context("could not find game folder")?;
+      .context("could not find folder")?;
 
     let folder = if let Some(folders) = folders.files && !folders.is_empty() {
       folders[0].clone()
     } else {
-      let remote = RemoteFile { name: Some(game), parents: Some(vec![self.folder.clone()]), mime_type: Some("application/vnd.google-apps.folder".to_string()), ..Default::default() };
+      let remote = RemoteFile {
+        name: Some(name.to_string()),
+        parents: Some(vec![parent.to_string()]),
+        mime_type: Some(FOLDER_MIME_TYPE.to_string()),
+        ..Default::default()
+      };
 
-      let (_, file) = self.hub.files().create(remote).upload(std::io::empty(), "application/vnd.google-apps.folder".parse().unwrap()).await.context("could not create game folder")?;
+      let (_, file) = self
+        .hub
+        .files()
+        .create(remote)
+        .add_scope(self.scope)
+        .upload(std::io::empty(), FOLDER_MIME_TYPE.parse().unwrap())
+        .await
+        .context("could not create folder")?;
 
       file
     };
 
-    let file = File::open(&screenshot.path)?;
+    folder.id.ok_or_else(|| anyhow!("folder has no identifier"))
+  }
+}
+
+#[async_trait]
+impl Uploader for GoogleDriveUploader {
+  fn name(&self) -> &'static str {
+    "Google Drive"
+  }
+
+  fn endpoint(&self) -> Option<&str> {
+    Some("https://www.googleapis.com")
+  }
+
+  async fn upload(&self, screenshot: &GameScreenshot, throttle: &Throttle) -> Result<String, anyhow::Error> {
+    self.ensure_tokens().await?;
+
+    let filename = screenshot.remote_name()?;
+    let media_type = screenshot.media_type()?;
+    let game = screenshot.game_name().await;
+
+    // With user credentials, deckshot can only see the files it created, so it
+    // uses its own folder at the root of the drive unless told otherwise.
+    let root = match self.folder {
+      Some(ref folder) => folder.clone(),
+      None => self.find_or_create_folder(DEFAULT_FOLDER, "root").await?,
+    };
+
+    let folder = self.find_or_create_folder(&game, &root).await.context("could not find game folder")?;
+    let file = throttle.blocking_reader(File::open(&screenshot.path)?);
 
     let remote = RemoteFile {
-      parents: Some(vec![folder.id.unwrap()]),
-      name: Some(filename.to_string_lossy().into_owned()),
+      parents: Some(vec![folder]),
+      name: Some(filename),
+      mime_type: Some(media_type.mime.to_string()),
       ..Default::default()
     };
 
-    self.hub.files().create(remote).upload(file, "image/jpeg".parse().unwrap()).await.context("could not upload file")?;
+    let (_, file) = self
+      .hub
+      .files()
+      .create(remote)
+      .add_scope(self.scope)
+      .upload(file, media_type.mime.parse()?)
+      .await
+      .context("could not upload file")?;
 
-    Ok(screenshot)
+    Ok(file.id.unwrap_or_default())
   }
+
+  async fn check(&self) -> Result<Health, anyhow::Error> {
+    self.ensure_tokens().await?;
+
+    self
+      .hub
+      .about()
+      .get()
+      .param("fields", "user")
+      .add_scope(self.scope)
+      .doit()
+      .await
+      .context("could not get account information")?;
+
+    Ok(Health::Valid)
+  }
+
+  async fn auth(&self) -> Result<(), anyhow::Error> {
+    let user = match self.user {
+      Some(ref user) => user,
+      None => return Ok(()),
+    };
+
+    let secret = user.secret.clone();
+
+    let auth = match self.flow {
+      AuthFlow::Redirect => {
+        oauth2::InstalledFlowAuthenticator::builder(secret, InstalledFlowReturnMethod::HTTPRedirect)
+          .with_storage(Box::new(FlowStorage(user.clone())))
+          .build()
+          .await?
+      }
+
+      AuthFlow::Device => {
+        oauth2::DeviceFlowAuthenticator::builder(secret)
+          .flow_delegate(Box::new(DeviceCodePresenter))
+          .with_storage(Box::new(FlowStorage(user.clone())))
+          .build()
+          .await?
+      }
+    };
+
+    auth.token(&[SCOPE_FILE]).await.context("could not authenticate with Google")?;
+
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl Refresher for UserTokens {
+  async fn refresh(&self, credential: &Credential) -> Result<Credential, anyhow::Error> {
+    tokens::refresh_oauth(&self.client, self.tokens.key(), credential).await
+  }
+}
+
+/// Hands the stored access token to the hub. Unlike the authenticators of
+/// yup-oauth2, it never falls back to an interactive flow.
+#[derive(Clone)]
+struct StoredTokens(Arc<UserTokens>);
+
+impl GetToken for StoredTokens {
+  fn get_token<'a>(&'a self, _scopes: &'a [&str]) -> Pin<Box<dyn Future<Output = Result<Option<String>, Box<dyn Error + Send + Sync>>> + Send + 'a>> {
+    Box::pin(async move { Ok(Some(self.0.tokens.access_token(&*self.0).await?)) })
+  }
+}
+
+/// Receives the tokens obtained by the flows of `deckshot auth`. Nothing is
+/// ever returned, so that the flow always runs.
+struct FlowStorage(Arc<UserTokens>);
+
+#[async_trait]
+impl TokenStorage for FlowStorage {
+  async fn set(&self, _scopes: &[&str], token: TokenInfo) -> Result<(), anyhow::Error> {
+    self.0.tokens.save(credential(token)).await
+  }
+
+  async fn get(&self, _scopes: &[&str]) -> Option<TokenInfo> {
+    None
+  }
+}
+
+/// Only the `drive.file` scope is ever requested.
+fn credential(token: TokenInfo) -> Credential {
+  Credential {
+    access_token: token.access_token.unwrap_or_default(),
+    refresh_token: token.refresh_token,
+    expires_at: token.expires_at.map(|expires_at| expires_at.unix_timestamp().max(0) as u64),
+    scopes: vec![SCOPE_FILE.to_string()],
+    needs_auth: false,
+  }
+}
+
+struct DeviceCodePresenter;
+
+impl DeviceFlowDelegate for DeviceCodePresenter {
+  fn present_user_code<'a>(&'a self, response: &'a DeviceAuthResponse) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+    Box::pin(async move { flows::present_device_code(&response.verification_uri, &response.user_code, None) })
+  }
+}
+
+async fn read_client_secret(path: &Option<String>) -> Result<oauth2::ApplicationSecret, anyhow::Error> {
+  let path = path.as_ref().ok_or_else(|| anyhow!("'client_secret_file' is required with OAuth"))?;
+
+  helpers::read_application_secret(path).await.context(format!("could not read client secret: {path}"))
+}
+
+fn https_client() -> hyper::Client<HttpsConnector<HttpConnector>> {
+  hyper::Client::builder().build(hyper_rustls::HttpsConnectorBuilder::new().with_native_roots().https_or_http().enable_http1().enable_http2().build())
 }
@@ -1,28 +1,62 @@
-use std::{
-  fs::File,
-  io,
-  time::{Duration, SystemTime},
-};
+use std::{fs::File, io, sync::Arc, time::Duration};
 
 use anyhow::anyhow;
 use imgurs_client::{
   client::BasicClient,
-  endpoints::authorization::{AuthenticationClient, AuthenticationRegisteredClient, Method},
-  traits::{Client, RegisteredClient},
+  endpoints::authorization::{AuthenticationClient, Method},
 };
-use imgurs_model::model::authorization::{AccessToken, AuthorizationCode, ClientID, ClientSecret, RefreshToken};
+use imgurs_model::model::authorization::{ClientID, ClientSecret};
+use reqwest::{header::CONTENT_LENGTH, Body};
 use serde::Deserialize;
 
 use crate::{
-  config::Config,
-  database::{load_token, save_token},
-  uploaders::prompt_authorization_code,
+  bandwidth::Throttle,
+  secrets::SecretStore,
+  uploaders::{
+    oauth,
+    tokens::{AuthError, Credential, Refresher, TokenStore},
+    Health,
+  },
   GameScreenshot, Uploader,
 };
 
+const TOKEN_URL: &str = "https://api.imgur.com/oauth2/token";
+
+#[derive(Deserialize)]
+struct UploadResponse {
+  data: UploadedImage,
+}
+
+#[derive(Deserialize)]
+struct UploadedImage {
+  link: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  access_token: String,
+  refresh_token: Option<String>,
+  expires_in: Option<u64>,
+  scope: Option<String>,
+}
+
+impl From<TokenResponse> for Credential {
+  fn from(tokens: TokenResponse) -> Credential {
+    Credential::new(
+      tokens.access_token,
+      tokens.refresh_token,
+      tokens.expires_in.map(Duration::from_secs),
+      tokens.scope.map(|scope| scope.split_whitespace().map(str::to_string).collect()).unwrap_or_default(),
+    )
+  }
+}
+
 pub struct ImgurUploader {
-  config: Config,
+  tokens: TokenStore,
   client: BasicClient,
+  client_id: String,
+  client_secret: String,
+  redirect_uri: String,
 }
 
 #[derive(Clone, Deserialize)]
@@ -33,10 +67,37 @@ pub struct ImgurConfig {
 }
 
 impl ImgurUploader {
-  pub fn build(deckshot: &Config, config: ImgurConfig) -> Result<ImgurUploader, anyhow::Error> {
-    let client = BasicClient::new(ClientID(config.client_id), ClientSecret(config.client_secret)).map_err(|err| anyhow!(err.to_string()))?;
+  pub fn build(secrets: &Arc<SecretStore>, name: &str, config: ImgurConfig) -> Result<ImgurUploader, anyhow::Error> {
+    let client = BasicClient::new(ClientID(config.client_id.clone()), ClientSecret(config.client_secret.clone())).map_err(|err| anyhow!(err.to_string()))?;
+
+    Ok(ImgurUploader {
+      tokens: TokenStore::new(secrets, name),
+      client,
+      client_id: config.client_id,
+      client_secret: config.client_secret,
+      redirect_uri: config.redirect_uri,
+    })
+  }
+
+  async fn request_tokens(&self, grant: &[(&str, &str)]) -> Result<reqwest::Response, anyhow::Error> {
+    let mut body = vec![("client_id", self.client_id.as_str()), ("client_secret", self.client_secret.as_str())];
+    body.extend_from_slice(grant);
 
-    Ok(ImgurUploader { config: deckshot.clone(), client })
+    Ok(reqwest::Client::new().post(TOKEN_URL).form(&body).send().await?)
+  }
+}
+
+#[async_trait]
+impl Refresher for ImgurUploader {
+  async fn refresh(&self, credential: &Credential) -> Result<Credential, anyhow::Error> {
+    let refresh_token = credential.refresh_token.clone().unwrap_or_default();
+    let response = self.request_tokens(&[("grant_type", "refresh_token"), ("refresh_token", &refresh_token)]).await?;
+
+    match response.status().as_u16() {
+      200 => Ok(response.json::<TokenResponse>().await?.into()),
+      code @ (400 | 401 | 403) => Err(AuthError::new(self.tokens.key(), format!("refresh responded with code {code}")).into()),
+      code => Err(anyhow!(format!("refresh responded with code {code}"))),
+    }
   }
 }
 
@@ -46,28 +107,12 @@ impl Uploader for ImgurUploader {
     "imgur"
   }
 
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
-    let access_token = AccessToken(load_token(&self.config, "imgur-access-token").await?);
-    let refresh_token = RefreshToken(load_token(&self.config, "imgur-refresh-token").await?);
-    let expires_in = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(load_token(&self.config, "imgur-expires-in").await?.parse::<u64>()?);
-
-    let auth = self
-      .client
-      .clone()
-      .with_tokens(access_token.clone(), refresh_token, expires_in)
-      .map_err(|err| anyhow!(err.to_string()))?;
-
-    let auth = auth.with_fresh_tokens().await?;
-
-    if auth.get_authentication_settings().access_token != access_token {
-      let expires_in = (SystemTime::now() + Duration::from_secs(14 * 24 * 60 * 60)).duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
-
-      save_token(&self.config, "imgur-access-token", &auth.get_authentication_settings().access_token.0).await?;
-      save_token(&self.config, "imgur-refresh-token", &auth.get_authentication_settings().refresh_token.0).await?;
-      save_token(&self.config, "imgur-expires-in", &expires_in.to_string()).await?;
-    }
+  fn endpoint(&self) -> Option<&str> {
+    Some("https://api.imgur.com")
+  }
 
-    let client = auth.get_client();
+  async fn upload(&self, screenshot: &GameScreenshot, throttle: &Throttle) -> Result<String, anyhow::Error> {
+    let token = self.tokens.access_token(self).await?;
 
     let mut file = File::open(&screenshot.path)?;
     let mut buffer = Vec::new();
@@ -82,24 +127,47 @@ impl Uploader for ImgurUploader {
     let body = [
       ("type", "base64"),
       ("image", &String::from_utf8_lossy(&buffer)),
-      ("name", &format!("{} - {}", screenshot.game_name().await, screenshot.file_name()?.to_string_lossy())),
+      ("name", &format!("{} - {}", screenshot.game_name().await, screenshot.remote_name()?)),
     ];
 
-    match client.post("https://api.imgur.com/3/upload").headers(auth.get_headers()?).form(&body).send().await?.status().as_u16() {
-      200 => Ok(screenshot),
+    let client = reqwest::Client::new();
+    let mut request = client.post("https://api.imgur.com/3/upload").bearer_auth(token).form(&body).build()?;
+
+    // The encoded form is streamed so its chunks follow the throttle.
+    if let Some(form) = request.body().and_then(|body| body.as_bytes()).map(<[u8]>::to_vec) {
+      request.headers_mut().insert(CONTENT_LENGTH, form.len().into());
+      *request.body_mut() = Some(Body::wrap_stream(throttle.stream(form)));
+    }
+
+    let response = client.execute(request).await?;
+
+    match response.status().as_u16() {
+      200 => Ok(response.json::<UploadResponse>().await?.data.link),
       code => Err(anyhow!(format!("upload responded with code {code}"))),
     }
   }
 
+  async fn check(&self) -> Result<Health, anyhow::Error> {
+    let token = self.tokens.access_token(self).await?;
+    let response = reqwest::Client::new().get("https://api.imgur.com/3/account/me").bearer_auth(token).send().await?;
+
+    match response.status().as_u16() {
+      200 => Ok(self.tokens.health().await),
+      code @ (401 | 403) => Err(AuthError::new(self.tokens.key(), format!("account responded with code {code}")).into()),
+      code => Err(anyhow!(format!("account responded with code {code}"))),
+    }
+  }
+
   async fn auth(&self) -> Result<(), anyhow::Error> {
-    let url = self.client.get_authentication_url(Method::AuthorizationCode, None)?;
-    let code = prompt_authorization_code(url.as_str())?;
-    let tokens = self.client.authorization_by_authorization_code(AuthorizationCode(code)).await?.content.result()?;
-    let expires_in = (SystemTime::now() + Duration::from_secs(14 * 24 * 60 * 60)).duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
-
-    save_token(&self.config, "imgur-access-token", &tokens.access_token.0).await?;
-    save_token(&self.config, "imgur-refresh-token", &tokens.refresh_token.0).await?;
-    save_token(&self.config, "imgur-expires-in", &expires_in.to_string()).await?;
+    let state = oauth::state();
+    let url = self.client.get_authentication_url(Method::AuthorizationCode, Some(state.clone()))?;
+    let code = oauth::authorization_code(url.as_str(), &self.redirect_uri, &state).await?;
+    let response = self.request_tokens(&[("grant_type", "authorization_code"), ("code", &code)]).await?;
+
+    match response.status().as_u16() {
+      200 => self.tokens.save(response.json::<TokenResponse>().await?.into()).await?,
+      code => return Err(anyhow!(format!("authorization responded with code {code}"))),
+    }
 
     Ok(())
   }
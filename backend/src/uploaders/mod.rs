@@ -3,34 +3,97 @@ pub mod dropbox;
 pub mod gdrive;
 pub mod imgur;
 pub mod noop;
+pub mod oauth;
 pub mod onedrive;
 pub mod s3;
+pub mod tokens;
 
-use std::io::{self, Write};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use anyhow::Context;
+use serde::Serialize;
 
-use crate::GameScreenshot;
+use crate::{
+  bandwidth::{Limiter, Throttle},
+  transforms::Pipeline,
+  uploaders::tokens::AuthError,
+  GameScreenshot,
+};
 
 #[async_trait]
 pub trait Uploader: Sync + Send {
   fn name(&self) -> &'static str;
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error>;
+  /// Address of the service, probed to hold uploads while it cannot be
+  /// reached.
+  fn endpoint(&self) -> Option<&str> {
+    None
+  }
+
+  /// Uploads a screenshot and returns its remote location (key, path, URL or
+  /// identifier, depending on the service). The request body is sent as the
+  /// throttle allows.
+  async fn upload(&self, screenshot: &GameScreenshot, throttle: &Throttle) -> Result<String, anyhow::Error>;
 
   async fn auth(&self) -> Result<(), anyhow::Error> {
-    unimplemented!();
+    Ok(())
+  }
+
+  /// Makes a cheap authenticated call to the service to verify the
+  /// credentials.
+  async fn check(&self) -> Result<Health, anyhow::Error> {
+    Ok(Health::Valid)
   }
 }
 
-pub fn prompt_authorization_code(authorize_url: &str) -> Result<String, anyhow::Error> {
-  println!("Open the following URL into your Web browser to authenticate, then input the generated code:");
-  println!("{authorize_url}");
+#[derive(Debug)]
+pub enum Health {
+  Valid,
+  /// The credentials cannot be renewed automatically, and expire after the
+  /// given duration.
+  Expiring(Duration),
+  Invalid {
+    reason: String,
+    hint: String,
+  },
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Receipt {
+  pub destination: String,
+  pub path: PathBuf,
+  pub location: String,
+  pub thumbnail: Option<String>,
+}
+
+/// A configured uploader, along with the transforms applied to screenshots
+/// before they are sent to it.
+pub struct Destination {
+  pub name: String,
+  pub uploader: Box<dyn Uploader>,
+  pub pipeline: Pipeline,
+  /// Bandwidth limit of this destination only.
+  pub limiter: Arc<Limiter>,
+}
 
-  let mut code = String::new();
+impl Destination {
+  /// Database list holding the screenshots that failed to upload to this
+  /// destination.
+  pub fn queue(&self) -> String {
+    format!("screenshots:{}", self.name)
+  }
+
+  pub async fn health(&self) -> Health {
+    match self.uploader.check().await {
+      Ok(health) => health,
 
-  print!("Code: ");
-  io::stdout().flush()?;
-  io::stdin().read_line(&mut code).context("could not read code")?;
+      Err(err) => {
+        let hint = if err.downcast_ref::<AuthError>().is_some() {
+          format!("run 'deckshot auth {}'", self.name)
+        } else {
+          "check the configuration of this uploader and the network connection".to_string()
+        };
 
-  Ok(code.trim().to_string())
+        Health::Invalid { reason: format!("{err:#}"), hint }
+      }
+    }
+  }
 }
@@ -1,7 +1,7 @@
 use anyhow::anyhow;
 use rand::{thread_rng, Rng};
 
-use crate::{GameScreenshot, Uploader};
+use crate::{bandwidth::Throttle, GameScreenshot, Uploader};
 
 #[derive(Clone)]
 pub struct NoopUploader;
@@ -18,9 +18,9 @@ impl Uploader for NoopUploader {
     "noop"
   }
 
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
+  async fn upload(&self, screenshot: &GameScreenshot, _throttle: &Throttle) -> Result<String, anyhow::Error> {
     match thread_rng().gen::<bool>() {
-      true => Ok(screenshot),
+      true => Ok(screenshot.path.to_string_lossy().into_owned()),
       false => Err(anyhow!("upload failed!")),
     }
   }
@@ -0,0 +1,184 @@
+use std::{
+  io::{self, Write},
+  time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use oauth2::{
+  basic::{BasicClient, BasicTokenResponse},
+  reqwest::async_http_client,
+  Scope, StandardDeviceAuthorizationResponse,
+};
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::{
+  io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+  net::{TcpListener, TcpStream},
+  sync::mpsc,
+};
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFlow {
+  /// Open the authorization URL in a browser and receive the code on the
+  /// redirect URI.
+  #[default]
+  Redirect,
+  /// Sign in on another device with a short code (RFC 8628).
+  Device,
+}
+
+const LOOPBACK_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]"];
+
+/// Time given to the user to complete the authorization in their browser.
+const AUTHORIZATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const SUCCESS_PAGE: &str = "<!DOCTYPE html><html><body><p>Deckshot is now authenticated, you can close this window.</p></body></html>";
+const FAILURE_PAGE: &str = "<!DOCTYPE html><html><body><p>Deckshot could not be authenticated, check the terminal for details.</p></body></html>";
+
+/// Generates a random value to be used as the `state` parameter of an
+/// authorization request.
+pub fn state() -> String {
+  thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect()
+}
+
+/// Obtains an authorization code by listening on the redirect URI when it
+/// points to the loopback interface, falling back to prompting the user for it
+/// otherwise. The returned `state` must match the one given to the provider.
+pub async fn authorization_code(authorize_url: &str, redirect_uri: &str, state: &str) -> Result<String, anyhow::Error> {
+  let redirect = Url::parse(redirect_uri).context("could not parse redirect URI")?;
+
+  match redirect.host_str() {
+    Some(host) if LOOPBACK_HOSTS.contains(&host) => listen_for_code(authorize_url, &redirect, state).await,
+    _ => prompt_authorization_code(authorize_url),
+  }
+}
+
+async fn listen_for_code(authorize_url: &str, redirect: &Url, state: &str) -> Result<String, anyhow::Error> {
+  let port = redirect.port_or_known_default().unwrap_or(80);
+  let address = if redirect.host_str() == Some("[::1]") { "::1" } else { "127.0.0.1" };
+  let listener = TcpListener::bind((address, port)).await.context(format!("could not listen on port {port}"))?;
+
+  println!("Open the following URL into your Web browser to authenticate:");
+  println!("{authorize_url}");
+  println!("Waiting for the authorization to complete...");
+
+  let (sender, mut receiver) = mpsc::channel(1);
+
+  // Connections are handled concurrently, since browsers may open some ahead
+  // of time and only send a request on them later, if ever.
+  let wait = async {
+    loop {
+      tokio::select! {
+        accepted = listener.accept() => {
+          let (stream, _) = accepted.context("could not accept connection")?;
+
+          tokio::spawn(answer_redirect(stream, redirect.clone(), state.to_string(), sender.clone()));
+        }
+
+        Some(result) = receiver.recv() => return result,
+      }
+    }
+  };
+
+  tokio::time::timeout(AUTHORIZATION_TIMEOUT, wait)
+    .await
+    .map_err(|_| anyhow!("authorization was not completed within {} minutes", AUTHORIZATION_TIMEOUT.as_secs() / 60))?
+}
+
+/// Answers a connection to the loopback listener, and reports the outcome of
+/// the authorization when it carries the redirect from the provider. Other
+/// requests, and connections without any, are ignored.
+async fn answer_redirect(mut stream: TcpStream, redirect: Url, state: String, sender: mpsc::Sender<Result<String, anyhow::Error>>) {
+  let mut line = String::new();
+
+  if BufReader::new(&mut stream).read_line(&mut line).await.is_err() {
+    return;
+  }
+
+  let url = match line.split_whitespace().nth(1).and_then(|target| redirect.join(target).ok()) {
+    Some(url) => url,
+    None => return,
+  };
+
+  if url.path() != redirect.path() || !url.query_pairs().any(|(key, _)| key == "code" || key == "error") {
+    let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n").await;
+
+    return;
+  }
+
+  let result = parse_redirect(&url, &state);
+  let page = if result.is_ok() { SUCCESS_PAGE } else { FAILURE_PAGE };
+  let response = format!(
+    "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{page}",
+    page.len()
+  );
+
+  let _ = stream.write_all(response.as_bytes()).await;
+  let _ = sender.send(result).await;
+}
+
+fn parse_redirect(url: &Url, state: &str) -> Result<String, anyhow::Error> {
+  let param = |name: &str| url.query_pairs().find(|(key, _)| key == name).map(|(_, value)| value.into_owned());
+
+  if let Some(error) = param("error") {
+    return Err(anyhow!("authorization was denied: {}", param("error_description").unwrap_or(error)));
+  }
+
+  if param("state").as_deref() != Some(state) {
+    return Err(anyhow!("authorization state did not match, the request might have been forged"));
+  }
+
+  param("code").ok_or_else(|| anyhow!("authorization response did not contain a code"))
+}
+
+/// Runs the device authorization grant, displaying the verification URL (as a
+/// QR code as well) and the code to enter there, then polls the token endpoint
+/// until the user signed in.
+pub async fn device_code(client: &BasicClient, scopes: &[&str]) -> Result<BasicTokenResponse, anyhow::Error> {
+  let details: StandardDeviceAuthorizationResponse = client
+    .exchange_device_code()?
+    .add_scopes(scopes.iter().map(|scope| Scope::new(scope.to_string())))
+    .request_async(async_http_client)
+    .await
+    .context("could not request a device code")?;
+
+  present_device_code(
+    details.verification_uri().as_str(),
+    details.user_code().secret(),
+    details.verification_uri_complete().map(|url| url.secret().as_str()),
+  );
+
+  let tokens = client
+    .exchange_device_access_token(&details)
+    .request_async(async_http_client, tokio::time::sleep, Some(details.expires_in()))
+    .await
+    .context("could not complete device authorization")?;
+
+  Ok(tokens)
+}
+
+pub fn present_device_code(verification_uri: &str, user_code: &str, verification_uri_complete: Option<&str>) {
+  println!("Scan the following QR code or open {verification_uri} on any device, and enter the code {user_code}:");
+
+  if let Ok(code) = QrCode::new(verification_uri_complete.unwrap_or(verification_uri).as_bytes()) {
+    println!("{}", code.render::<Dense1x2>().dark_color(Dense1x2::Light).light_color(Dense1x2::Dark).build());
+  }
+
+  println!("Waiting for the authorization to complete...");
+}
+
+pub fn prompt_authorization_code(authorize_url: &str) -> Result<String, anyhow::Error> {
+  println!("Open the following URL into your Web browser to authenticate, then input the generated code:");
+  println!("{authorize_url}");
+
+  let mut code = String::new();
+
+  print!("Code: ");
+  io::stdout().flush()?;
+  io::stdin().read_line(&mut code).context("could not read code")?;
+
+  Ok(code.trim().to_string())
+}
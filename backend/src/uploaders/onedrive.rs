@@ -1,61 +1,118 @@
-use oauth2::{
-  basic::BasicClient, reqwest::async_http_client, AccessToken, AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, RedirectUrl, RefreshToken, Scope, TokenResponse, TokenUrl,
-};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context};
+use oauth2::{basic::BasicClient, reqwest::async_http_client, AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, DeviceAuthorizationUrl, RedirectUrl, Scope, TokenUrl};
 use onedrive_api::{option::DriveItemPutOption, ConflictBehavior, DriveLocation, FileName, ItemLocation};
 use serde::Deserialize;
 use tokio::{fs::File, io::AsyncReadExt};
 
 use crate::{
-  config::Config,
-  database::{load_token, save_token},
-  uploaders::prompt_authorization_code,
+  bandwidth::Throttle,
+  secrets::SecretStore,
+  uploaders::{
+    oauth::{self, AuthFlow},
+    tokens::{self, AuthError, Credential, Refresher, TokenStore},
+    Health,
+  },
   GameScreenshot, Uploader,
 };
 
+const SCOPES: &[&str] = &["offline_access", "Files.ReadWrite"];
+
+/// Size of the parts of an upload session, which must be multiples of
+/// 320 KiB except for the last one.
+const PART_SIZE: usize = 16 * 320 * 1024;
+
+/// Largest file that can be sent in a single request.
+const SMALL_UPLOAD_SIZE: usize = 4 * 1024 * 1024;
+
 pub struct OneDriveUploader {
-  config: Config,
+  tokens: TokenStore,
   client: BasicClient,
+  redirect_uri: String,
+  flow: AuthFlow,
   folder: Option<String>,
 }
 
 #[derive(Clone, Deserialize)]
 pub struct OneDriveConfig {
   pub client_id: String,
-  pub client_secret: String,
+  /// Not needed with the device flow, where the application is a public client.
+  pub client_secret: Option<String>,
   pub redirect_uri: String,
+  #[serde(default)]
+  pub flow: AuthFlow,
   pub folder: Option<String>,
 }
 
 impl OneDriveUploader {
-  pub async fn build(deckshot: &Config, config: OneDriveConfig) -> Result<Self, anyhow::Error> {
+  pub async fn build(secrets: &Arc<SecretStore>, name: &str, config: OneDriveConfig) -> Result<Self, anyhow::Error> {
+    let client_secret = match config.flow {
+      AuthFlow::Redirect => Some(ClientSecret::new(config.client_secret.clone().context("a client secret is required with the redirect flow")?)),
+      AuthFlow::Device => None,
+    };
+
     let client = BasicClient::new(
       ClientId::new(config.client_id.clone()),
-      Some(ClientSecret::new(config.client_secret.clone())),
+      client_secret,
       AuthUrl::new("https://login.microsoftonline.com/consumers/oauth2/v2.0/authorize".to_string())?,
       Some(TokenUrl::new("https://login.microsoftonline.com/consumers/oauth2/v2.0/token".to_string())?),
     )
     .set_auth_type(AuthType::RequestBody)
-    .set_redirect_uri(RedirectUrl::new(config.redirect_uri.clone())?);
+    .set_redirect_uri(RedirectUrl::new(config.redirect_uri.clone())?)
+    .set_device_authorization_url(DeviceAuthorizationUrl::new("https://login.microsoftonline.com/consumers/oauth2/v2.0/devicecode".to_string())?);
 
     Ok(OneDriveUploader {
-      config: deckshot.clone(),
+      tokens: TokenStore::new(secrets, name),
       client,
+      redirect_uri: config.redirect_uri,
+      flow: config.flow,
       folder: config.folder,
     })
   }
+}
 
-  async fn try_upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
-    let token = AccessToken::new(load_token(&self.config, "onedrive-access-token").await?);
+impl OneDriveUploader {
+  /// Turns authentication failures into an `AuthError`.
+  fn unauthorized<T>(&self, result: Result<T, onedrive_api::Error>) -> Result<T, anyhow::Error> {
+    match result {
+      Ok(value) => Ok(value),
+      Err(err) if matches!(err.status_code().map(|status| status.as_u16()), Some(401 | 403)) => Err(AuthError::new(self.tokens.key(), err).into()),
+      Err(err) => Err(err.into()),
+    }
+  }
+}
+
+#[async_trait]
+impl Refresher for OneDriveUploader {
+  async fn refresh(&self, credential: &Credential) -> Result<Credential, anyhow::Error> {
+    tokens::refresh_oauth(&self.client, self.tokens.key(), credential).await
+  }
+}
+
+#[async_trait]
+impl Uploader for OneDriveUploader {
+  fn name(&self) -> &'static str {
+    "Microsoft OneDrive"
+  }
+
+  fn endpoint(&self) -> Option<&str> {
+    Some("https://graph.microsoft.com")
+  }
+
+  async fn upload(&self, screenshot: &GameScreenshot, throttle: &Throttle) -> Result<String, anyhow::Error> {
+    let token = self.tokens.access_token(self).await?;
 
     let game = screenshot.game_name().await;
-    let filename = FileName::new(screenshot.file_name()?.to_str().unwrap()).unwrap();
+    let name = screenshot.remote_name()?;
+    let filename = FileName::new(&name).unwrap();
 
     let mut file = File::open(&screenshot.path).await?;
     let mut buffer = Vec::new();
 
     file.read_to_end(&mut buffer).await?;
 
-    let drive = onedrive_api::OneDrive::new(token.secret(), DriveLocation::me());
+    let drive = onedrive_api::OneDrive::new(&token, DriveLocation::me());
 
     if let Some(ref folder) = self.folder {
       drive
@@ -77,48 +134,61 @@ impl OneDriveUploader {
     let folder_id = folder.id.unwrap();
     let item = ItemLocation::child_of_id(&folder_id, filename);
 
-    drive.upload_small(item, buffer).await?;
+    // The client sends every request body in one piece, so the file only goes
+    // through an upload session, one part at a time as bandwidth allows, when
+    // bandwidth is limited or the file is too large for a single request. The
+    // last part answers with the uploaded file.
+    let item = if buffer.len() > SMALL_UPLOAD_SIZE || (throttle.is_limited() && !buffer.is_empty()) {
+      let (session, _) = drive.new_upload_session(item).await?;
+      let size = buffer.len() as u64;
+      let mut offset = 0;
+      let mut uploaded = None;
 
-    Ok(screenshot)
-  }
-}
+      for part in buffer.chunks(PART_SIZE) {
+        throttle.acquire(part.len()).await;
 
-#[async_trait]
-impl Uploader for OneDriveUploader {
-  fn name(&self) -> &'static str {
-    "Microsoft OneDrive"
+        let end = offset + part.len() as u64;
+
+        uploaded = session.upload_part(part.to_vec(), offset..end, size, drive.client()).await?;
+        offset = end;
+      }
+
+      uploaded.ok_or_else(|| anyhow!("upload session did not return the uploaded file"))?
+    } else {
+      drive.upload_small(item, buffer).await?
+    };
+
+    Ok(item.web_url.or_else(|| item.id.map(|id| id.as_str().to_string())).unwrap_or_default())
   }
 
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
-    match self.try_upload(screenshot).await {
-      Ok(screenshot) => Ok(screenshot),
+  async fn check(&self) -> Result<Health, anyhow::Error> {
+    let token = self.tokens.access_token(self).await?;
 
-      Err(err) => {
-        if err.downcast_ref::<onedrive_api::Error>().is_some() {
-          let refresh = RefreshToken::new(load_token(&self.config, "onedrive-refresh-token").await?);
-          let tokens = self.client.exchange_refresh_token(&refresh).request_async(async_http_client).await?;
+    let drive = onedrive_api::OneDrive::new(&token, DriveLocation::me());
 
-          save_token(&self.config, "onedrive-access-token", tokens.access_token().secret()).await?;
-        }
+    self.unauthorized(drive.get_drive().await).context("could not get drive information")?;
 
-        Err(err)
-      }
-    }
+    Ok(self.tokens.health().await)
   }
 
   async fn auth(&self) -> Result<(), anyhow::Error> {
-    let (url, _) = self
-      .client
-      .authorize_url(CsrfToken::new_random)
-      .add_scope(Scope::new("offline_access".to_string()))
-      .add_scope(Scope::new("Files.ReadWrite".to_string()))
-      .url();
-
-    let code = prompt_authorization_code(url.as_str())?;
-    let tokens = self.client.exchange_code(AuthorizationCode::new(code)).request_async(async_http_client).await?;
-
-    save_token(&self.config, "onedrive-access-token", tokens.access_token().secret()).await?;
-    save_token(&self.config, "onedrive-refresh-token", tokens.refresh_token().unwrap().secret()).await?;
+    let tokens = match self.flow {
+      AuthFlow::Redirect => {
+        let (url, state) = self
+          .client
+          .authorize_url(CsrfToken::new_random)
+          .add_scopes(SCOPES.iter().map(|scope| Scope::new(scope.to_string())))
+          .url();
+
+        let code = oauth::authorization_code(url.as_str(), &self.redirect_uri, state.secret()).await?;
+
+        self.client.exchange_code(AuthorizationCode::new(code)).request_async(async_http_client).await?
+      }
+
+      AuthFlow::Device => oauth::device_code(&self.client, SCOPES).await?,
+    };
+
+    self.tokens.save(Credential::from(&tokens)).await?;
 
     Ok(())
   }
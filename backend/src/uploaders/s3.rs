@@ -1,12 +1,13 @@
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use s3::{creds::Credentials, Bucket, Region};
 use serde::Deserialize;
 use tokio::{fs::File, io::BufReader};
 
-use crate::{GameScreenshot, Uploader};
+use crate::{bandwidth::Throttle, uploaders::Health, GameScreenshot, Uploader};
 
 pub struct S3Uploader {
   bucket: Bucket,
+  endpoint: String,
 }
 
 #[derive(Clone, Deserialize)]
@@ -24,13 +25,13 @@ impl S3Uploader {
       &config.bucket,
       Region::Custom {
         region: config.region.unwrap_or_default(),
-        endpoint: config.endpoint,
+        endpoint: config.endpoint.clone(),
       },
       Credentials::new(Some(&config.access_key_id), Some(&config.secret_access_key), None, None, None)?,
     )?
     .with_path_style();
 
-    Ok(S3Uploader { bucket })
+    Ok(S3Uploader { bucket, endpoint: config.endpoint })
   }
 }
 
@@ -40,13 +41,54 @@ impl Uploader for S3Uploader {
     "S3"
   }
 
-  async fn upload<'a>(&'a self, screenshot: &'a GameScreenshot) -> Result<&'a GameScreenshot, anyhow::Error> {
+  fn endpoint(&self) -> Option<&str> {
+    Some(&self.endpoint)
+  }
+
+  async fn upload(&self, screenshot: &GameScreenshot, throttle: &Throttle) -> Result<String, anyhow::Error> {
     let dest = screenshot.dest_name().await?;
+    let media_type = screenshot.media_type()?;
     let file = File::open(&screenshot.path).await?;
-    let mut reader = BufReader::new(file);
+    let mut reader = throttle.reader(BufReader::new(file));
+
+    let mut bucket = self.bucket.clone();
+    bucket.add_header("Content-Disposition", &content_disposition(&screenshot.remote_name()?));
+
+    bucket
+      .put_object_stream_with_content_type(&mut reader, dest.to_string_lossy(), media_type.mime)
+      .await
+      .context("could not upload screenshot")?;
+
+    Ok(dest.to_string_lossy().into_owned())
+  }
 
-    self.bucket.put_object_stream(&mut reader, dest.to_string_lossy()).await.context("could not upload screenshot")?;
+  async fn check(&self) -> Result<Health, anyhow::Error> {
+    let (_, code) = self.bucket.location().await.context("could not reach bucket")?;
 
-    Ok(screenshot)
+    match code {
+      200 => Ok(Health::Valid),
+      code => Err(anyhow!("bucket responded with code {code}")),
+    }
   }
 }
+
+/// Encodes the file name as an RFC 6266 `filename*` parameter, with a plain ASCII fallback for older clients.
+fn content_disposition(name: &str) -> String {
+  let fallback: String = name
+    .chars()
+    .map(|c| match c {
+      ' '..='~' if c != '"' && c != '\\' => c,
+      _ => '_',
+    })
+    .collect();
+
+  let encoded: String = name
+    .bytes()
+    .map(|byte| match byte {
+      b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => (byte as char).to_string(),
+      _ => format!("%{byte:02X}"),
+    })
+    .collect();
+
+  format!("inline; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
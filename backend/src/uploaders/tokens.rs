@@ -0,0 +1,496 @@
+use std::{
+  fmt,
+  sync::Arc,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use kvlogger::*;
+use oauth2::{
+  basic::{BasicClient, BasicErrorResponseType, BasicTokenResponse},
+  reqwest::async_http_client,
+  RefreshToken, RequestTokenError, TokenResponse,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{secrets::SecretStore, uploaders::Health};
+
+/// Access tokens are renewed this long before they expire, so an upload never
+/// starts with a token about to become invalid.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// OAuth credentials for one account, as persisted in the credentials
+/// directory.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Credential {
+  pub access_token: String,
+  pub refresh_token: Option<String>,
+  /// Expiry of the access token, in seconds since the UNIX epoch.
+  pub expires_at: Option<u64>,
+  #[serde(default)]
+  pub scopes: Vec<String>,
+  /// Set when the refresh token was rejected, the user needs to run
+  /// `deckshot auth` again.
+  #[serde(default)]
+  pub needs_auth: bool,
+}
+
+impl Credential {
+  pub fn new(access_token: String, refresh_token: Option<String>, expires_in: Option<Duration>, scopes: Vec<String>) -> Credential {
+    Credential {
+      access_token,
+      refresh_token,
+      expires_at: expires_in.map(|expires_in| now() + expires_in.as_secs()),
+      scopes,
+      needs_auth: false,
+    }
+  }
+
+  fn expires_soon(&self) -> bool {
+    self.expires_at.map(|expires_at| now() + REFRESH_MARGIN.as_secs() >= expires_at).unwrap_or(false)
+  }
+
+  fn expired(&self) -> bool {
+    self.expires_at.map(|expires_at| now() >= expires_at).unwrap_or(false)
+  }
+}
+
+impl From<&BasicTokenResponse> for Credential {
+  fn from(tokens: &BasicTokenResponse) -> Credential {
+    Credential::new(
+      tokens.access_token().secret().clone(),
+      tokens.refresh_token().map(|token| token.secret().clone()),
+      tokens.expires_in(),
+      tokens.scopes().map(|scopes| scopes.iter().map(|scope| scope.to_string()).collect()).unwrap_or_default(),
+    )
+  }
+}
+
+/// Credentials are missing, or were revoked by the provider.
+#[derive(Debug)]
+pub struct AuthError {
+  pub key: String,
+  pub reason: String,
+}
+
+impl AuthError {
+  pub fn new(key: &str, reason: impl ToString) -> AuthError {
+    AuthError {
+      key: key.to_string(),
+      reason: reason.to_string(),
+    }
+  }
+}
+
+impl fmt::Display for AuthError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "credentials '{}' are not valid ({}), run 'deckshot auth'", self.key, self.reason)
+  }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Reads the credentials a previous version saved in another format, if any,
+/// and returns them along with the files they were read from.
+pub type Import = fn(&SecretStore, &str) -> Option<(Credential, Vec<String>)>;
+
+#[async_trait]
+pub trait Refresher: Sync + Send {
+  /// Exchanges the refresh token of a credential for a new access token.
+  /// Returns an `AuthError` if the refresh token was rejected.
+  async fn refresh(&self, credential: &Credential) -> Result<Credential, anyhow::Error>;
+}
+
+/// Keeps the credentials of an uploader, and renews them ahead of expiry.
+///
+/// The lock is held while refreshing, so that concurrent uploads wait for the
+/// new token instead of racing each other with the same refresh token.
+pub struct TokenStore {
+  secrets: Arc<SecretStore>,
+  key: String,
+  credential: Mutex<Option<Credential>>,
+  import: Import,
+}
+
+impl TokenStore {
+  pub fn new(secrets: &Arc<SecretStore>, key: &str) -> TokenStore {
+    TokenStore {
+      secrets: secrets.clone(),
+      key: key.to_string(),
+      credential: Mutex::new(None),
+      import: import_split,
+    }
+  }
+
+  /// Replaces the way credentials saved by previous versions are read.
+  pub fn with_import(mut self, import: Import) -> TokenStore {
+    self.import = import;
+    self
+  }
+
+  /// Name the credentials are stored under, the one of the destination they
+  /// belong to.
+  pub fn key(&self) -> &str {
+    &self.key
+  }
+
+  pub async fn save(&self, credential: Credential) -> Result<(), anyhow::Error> {
+    let mut cache = self.credential.lock().await;
+
+    self.persist(&credential)?;
+    *cache = Some(credential);
+
+    Ok(())
+  }
+
+  /// Returns a valid access token, refreshing it first if needed.
+  pub async fn access_token(&self, refresher: &dyn Refresher) -> Result<String, anyhow::Error> {
+    let mut cache = self.credential.lock().await;
+
+    let mut credential = match cache.take() {
+      Some(credential) => credential,
+      None => self.load()?,
+    };
+
+    if credential.needs_auth {
+      *cache = Some(credential);
+
+      return Err(AuthError::new(&self.key, "refresh token was rejected").into());
+    }
+
+    if credential.expires_soon() {
+      match credential.refresh_token.clone() {
+        None => credential.needs_auth = credential.expired(),
+
+        Some(refresh_token) => match refresher.refresh(&credential).await {
+          Ok(mut renewed) => {
+            // Providers may not rotate the refresh token.
+            renewed.refresh_token = renewed.refresh_token.or(Some(refresh_token));
+
+            if renewed.scopes.is_empty() {
+              renewed.scopes = credential.scopes.clone();
+            }
+
+            self.persist(&renewed)?;
+            credential = renewed;
+          }
+
+          Err(err) if err.downcast_ref::<AuthError>().is_some() => {
+            credential.needs_auth = true;
+
+            self.persist(&credential)?;
+            *cache = Some(credential);
+
+            return Err(err);
+          }
+
+          // The current token can still be used until it actually expires.
+          Err(err) if credential.expired() => {
+            *cache = Some(credential);
+
+            return Err(err.context("could not refresh access token"));
+          }
+
+          Err(err) => {
+            kvlog!(Warn, "could not refresh access token", {
+                "credentials" => self.key,
+                "error" => format!("{err:#}")
+            });
+          }
+        },
+      }
+    }
+
+    if credential.needs_auth {
+      *cache = Some(credential);
+
+      return Err(AuthError::new(&self.key, "access token expired").into());
+    }
+
+    let token = credential.access_token.clone();
+    *cache = Some(credential);
+
+    Ok(token)
+  }
+
+  /// Reports credentials that cannot be refreshed as expiring, once they were
+  /// loaded by a call to `access_token`.
+  pub async fn health(&self) -> Health {
+    match *self.credential.lock().await {
+      Some(Credential {
+        refresh_token: None,
+        expires_at: Some(expires_at),
+        ..
+      }) => Health::Expiring(Duration::from_secs(expires_at.saturating_sub(now()))),
+
+      _ => Health::Valid,
+    }
+  }
+
+  fn load(&self) -> Result<Credential, anyhow::Error> {
+    match self.secrets.load(&self.file_name()) {
+      Ok(value) => serde_json::from_str(&value).context(format!("could not parse credentials '{}'", self.key)),
+
+      Err(_) => match (self.import)(&self.secrets, &self.key) {
+        Some((credential, files)) => {
+          self.migrate(&credential, &files)?;
+
+          Ok(credential)
+        }
+
+        None => Err(AuthError::new(&self.key, "no credentials were saved").into()),
+      },
+    }
+  }
+
+  /// Saves imported credentials in the current format, then removes the files
+  /// they came from, which may not be encrypted.
+  fn migrate(&self, credential: &Credential, files: &[String]) -> Result<(), anyhow::Error> {
+    self.persist(credential)?;
+
+    for file in files {
+      if let Err(err) = self.secrets.remove(file) {
+        kvlog!(Warn, "could not remove migrated credentials", {
+            "credentials" => self.key,
+            "file" => file,
+            "error" => format!("{err:#}")
+        });
+      }
+    }
+
+    kvlog!(Info, "credentials migrated", {
+        "credentials" => self.key
+    });
+
+    Ok(())
+  }
+
+  fn persist(&self, credential: &Credential) -> Result<(), anyhow::Error> {
+    self
+      .secrets
+      .save(&self.file_name(), &serde_json::to_string(credential)?)
+      .context(format!("could not save credentials '{}'", self.key))
+  }
+
+  fn file_name(&self) -> String {
+    format!("{}.json", self.key)
+  }
+}
+
+/// Tokens used to be saved in separate files without their expiry, they are
+/// treated as expired so that they get refreshed on first use.
+fn import_split(secrets: &SecretStore, key: &str) -> Option<(Credential, Vec<String>)> {
+  let files = vec![format!("{key}-access-token"), format!("{key}-refresh-token")];
+  let access_token = secrets.load(&files[0]).ok()?;
+  let refresh_token = secrets.load(&files[1]).ok()?;
+
+  let credential = Credential {
+    access_token,
+    refresh_token: Some(refresh_token),
+    expires_at: Some(0),
+    ..Default::default()
+  };
+
+  Some((credential, files))
+}
+
+/// Refreshes a credential against a standard OAuth2 token endpoint.
+pub async fn refresh_oauth(client: &BasicClient, key: &str, credential: &Credential) -> Result<Credential, anyhow::Error> {
+  let refresh_token = RefreshToken::new(credential.refresh_token.clone().unwrap_or_default());
+
+  match client.exchange_refresh_token(&refresh_token).request_async(async_http_client).await {
+    Ok(tokens) => Ok(Credential::from(&tokens)),
+
+    Err(RequestTokenError::ServerResponse(response)) if *response.error() == BasicErrorResponseType::InvalidGrant => Err(AuthError::new(key, response).into()),
+
+    Err(err) => Err(anyhow::Error::new(err).context("could not refresh access token")),
+  }
+}
+
+fn now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use anyhow::anyhow;
+
+  use super::*;
+  use crate::testing::TempTree;
+
+  enum Outcome {
+    Renew,
+    Reject,
+    Fail,
+  }
+
+  struct FakeRefresher {
+    outcome: Outcome,
+    calls: AtomicUsize,
+  }
+
+  impl FakeRefresher {
+    fn new(outcome: Outcome) -> FakeRefresher {
+      FakeRefresher { outcome, calls: AtomicUsize::new(0) }
+    }
+
+    fn calls(&self) -> usize {
+      self.calls.load(Ordering::SeqCst)
+    }
+  }
+
+  #[async_trait]
+  impl Refresher for FakeRefresher {
+    async fn refresh(&self, _credential: &Credential) -> Result<Credential, anyhow::Error> {
+      self.calls.fetch_add(1, Ordering::SeqCst);
+      tokio::time::sleep(Duration::from_millis(20)).await;
+
+      match self.outcome {
+        Outcome::Renew => Ok(Credential::new("renewed".to_string(), None, Some(Duration::from_secs(3600)), Vec::new())),
+        Outcome::Reject => Err(AuthError::new("test", "invalid_grant").into()),
+        Outcome::Fail => Err(anyhow!("network is unreachable")),
+      }
+    }
+  }
+
+  fn store(tree: &TempTree) -> TokenStore {
+    TokenStore::new(&Arc::new(SecretStore::at(tree.root())), "test")
+  }
+
+  fn credential(refresh_token: Option<&str>, expires_in: u64) -> Credential {
+    Credential {
+      access_token: "current".to_string(),
+      refresh_token: refresh_token.map(str::to_string),
+      expires_at: Some(now() + expires_in),
+      scopes: vec!["files".to_string()],
+      needs_auth: false,
+    }
+  }
+
+  #[tokio::test]
+  async fn keeps_token_outside_refresh_margin() {
+    let tree = TempTree::new("tokens-keep");
+    let tokens = store(&tree);
+    let refresher = FakeRefresher::new(Outcome::Renew);
+
+    tokens.save(credential(Some("refresh"), 3600)).await.unwrap();
+
+    assert_eq!(tokens.access_token(&refresher).await.unwrap(), "current");
+    assert_eq!(refresher.calls(), 0);
+  }
+
+  #[tokio::test]
+  async fn refreshes_ahead_of_expiry() {
+    let tree = TempTree::new("tokens-refresh");
+    let tokens = store(&tree);
+    let refresher = FakeRefresher::new(Outcome::Renew);
+
+    tokens.save(credential(Some("refresh"), 60)).await.unwrap();
+
+    assert_eq!(tokens.access_token(&refresher).await.unwrap(), "renewed");
+    assert_eq!(refresher.calls(), 1);
+
+    let saved = store(&tree).load().unwrap();
+
+    assert_eq!(saved.access_token, "renewed");
+    assert_eq!(saved.refresh_token.as_deref(), Some("refresh"));
+    assert_eq!(saved.scopes, vec!["files".to_string()]);
+  }
+
+  #[tokio::test]
+  async fn keeps_token_when_refresh_fails_before_expiry() {
+    let tree = TempTree::new("tokens-fail");
+    let tokens = store(&tree);
+    let refresher = FakeRefresher::new(Outcome::Fail);
+
+    tokens.save(credential(Some("refresh"), 60)).await.unwrap();
+
+    assert_eq!(tokens.access_token(&refresher).await.unwrap(), "current");
+
+    tokens.save(credential(Some("refresh"), 0)).await.unwrap();
+
+    let err = tokens.access_token(&refresher).await.unwrap_err();
+
+    assert!(err.downcast_ref::<AuthError>().is_none());
+  }
+
+  #[tokio::test]
+  async fn rejected_refresh_token_needs_auth() {
+    let tree = TempTree::new("tokens-reject");
+    let tokens = store(&tree);
+    let refresher = FakeRefresher::new(Outcome::Reject);
+
+    tokens.save(credential(Some("refresh"), 60)).await.unwrap();
+
+    assert!(tokens.access_token(&refresher).await.unwrap_err().downcast_ref::<AuthError>().is_some());
+    assert!(store(&tree).load().unwrap().needs_auth);
+
+    // Credentials flagged as needing authentication are not refreshed again.
+    assert!(store(&tree).access_token(&refresher).await.unwrap_err().downcast_ref::<AuthError>().is_some());
+    assert_eq!(refresher.calls(), 1);
+  }
+
+  #[tokio::test]
+  async fn expired_token_without_refresh_token_needs_auth() {
+    let tree = TempTree::new("tokens-expired");
+    let tokens = store(&tree);
+    let refresher = FakeRefresher::new(Outcome::Renew);
+
+    tokens.save(credential(None, 0)).await.unwrap();
+
+    assert!(tokens.access_token(&refresher).await.unwrap_err().downcast_ref::<AuthError>().is_some());
+    assert_eq!(refresher.calls(), 0);
+  }
+
+  #[tokio::test]
+  async fn reports_expiry_of_tokens_that_cannot_be_refreshed() {
+    let tree = TempTree::new("tokens-health");
+    let tokens = store(&tree);
+    let refresher = FakeRefresher::new(Outcome::Renew);
+
+    tokens.save(credential(None, 7200)).await.unwrap();
+    tokens.access_token(&refresher).await.unwrap();
+
+    assert!(matches!(tokens.health().await, Health::Expiring(left) if left > Duration::from_secs(7000)));
+
+    tokens.save(credential(Some("refresh"), 7200)).await.unwrap();
+
+    assert!(matches!(tokens.health().await, Health::Valid));
+  }
+
+  #[tokio::test]
+  async fn refreshes_imported_tokens_on_first_use() {
+    let tree = TempTree::new("tokens-import");
+    let secrets = Arc::new(SecretStore::at(tree.root()));
+    let refresher = FakeRefresher::new(Outcome::Renew);
+
+    secrets.save("test-access-token", "old").unwrap();
+    secrets.save("test-refresh-token", "refresh").unwrap();
+
+    let tokens = TokenStore::new(&secrets, "test");
+
+    assert_eq!(tokens.access_token(&refresher).await.unwrap(), "renewed");
+    assert_eq!(refresher.calls(), 1);
+    assert!(secrets.exists("test.json"));
+    assert!(!secrets.exists("test-access-token"));
+    assert!(!secrets.exists("test-refresh-token"));
+  }
+
+  #[tokio::test]
+  async fn concurrent_calls_refresh_once() {
+    let tree = TempTree::new("tokens-concurrent");
+    let tokens = store(&tree);
+    let refresher = FakeRefresher::new(Outcome::Renew);
+
+    tokens.save(credential(Some("refresh"), 60)).await.unwrap();
+
+    let (first, second) = tokio::join!(tokens.access_token(&refresher), tokens.access_token(&refresher));
+
+    assert_eq!(first.unwrap(), "renewed");
+    assert_eq!(second.unwrap(), "renewed");
+    assert_eq!(refresher.calls(), 1);
+  }
+}
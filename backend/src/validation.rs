@@ -0,0 +1,236 @@
+use std::{collections::HashSet, fmt, path::Path, sync::Arc};
+
+use reqwest::Url;
+
+use crate::{
+  config::{locate, Config, DestinationConfig, UploaderKind},
+  secrets::{self, SecretStore},
+  transforms::overlay::DEFAULT_FONTS,
+  uploaders::{gdrive::GoogleDriveAuth, oauth::AuthFlow},
+};
+
+/// A configuration mistake, along with the location of the offending value.
+pub struct Problem {
+  pub location: String,
+  pub message: String,
+  /// Line and column of the value in the configuration file, when found.
+  pub position: Option<(usize, usize)>,
+}
+
+impl fmt::Display for Problem {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.position {
+      Some((line, column)) => write!(f, "{} (line {line}, column {column}): {}", self.location, self.message),
+      None => write!(f, "{}: {}", self.location, self.message),
+    }
+  }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+  fn push(&mut self, location: &str, message: impl ToString) {
+    self.0.push(Problem {
+      location: location.to_string(),
+      message: message.to_string(),
+      position: None,
+    });
+  }
+
+  fn required(&mut self, location: &str, field: &str, value: &str) {
+    if value.trim().is_empty() {
+      self.push(&format!("{location}.{field}"), "must not be empty");
+    }
+  }
+
+  fn url(&mut self, location: &str, field: &str, value: &str) {
+    match Url::parse(value) {
+      Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+      Ok(url) => self.push(&format!("{location}.{field}"), format!("unsupported URL scheme '{}'", url.scheme())),
+      Err(err) => self.push(&format!("{location}.{field}"), format!("invalid URL '{value}': {err}")),
+    }
+  }
+
+  fn rate(&mut self, location: &str, value: Option<u64>) {
+    if value == Some(0) {
+      self.push(location, "must be positive, leave it out for no limit");
+    }
+  }
+
+  fn file(&mut self, location: &str, field: &str, value: &str) {
+    if !Path::new(value).is_file() {
+      self.push(&format!("{location}.{field}"), format!("file does not exist: {value}"));
+    }
+  }
+}
+
+/// Checks the configuration without contacting any service, and returns all
+/// the problems found.
+pub async fn validate(config: &Config) -> Vec<Problem> {
+  let mut problems = Problems::default();
+
+  if !config.screenshots_path.is_dir() {
+    problems.push("screenshots_path", format!("directory does not exist: {}", config.screenshots_path.display()));
+  }
+
+  for (index, window) in config.quiet_hours.iter().enumerate() {
+    if window.start == window.end {
+      problems.push(&format!("quiet_hours[{index}].end"), "must differ from start");
+    }
+  }
+
+  if config.power.min_battery_percent.map_or(false, |percent| percent > 100) {
+    problems.push("power.min_battery_percent", "must be between 0 and 100");
+  }
+
+  problems.rate("bandwidth_limit", config.bandwidth_limit);
+  problems.rate("game_mode.bandwidth_limit", config.game_mode.bandwidth_limit);
+
+  if config.connectivity.timeout == 0 {
+    problems.push("connectivity.timeout", "must be positive");
+  }
+
+  let destinations = config.destination_configs();
+
+  if destinations.is_empty() {
+    problems.push("uploaders", "no uploader configured");
+  }
+
+  let mut names = HashSet::new();
+  let mut resolvable = Vec::new();
+
+  for (location, destination) in destinations {
+    if !names.insert(destination.name()) {
+      problems.push(&format!("{location}.name"), format!("duplicate uploader name '{}'", destination.name()));
+    }
+
+    let count = problems.0.len();
+
+    validate_destination(&mut problems, &location, destination);
+
+    // Building a destination would only report the first of the problems
+    // already found.
+    if problems.0.len() == count {
+      resolvable.push((location, destination));
+    }
+  }
+
+  match SecretStore::open_read_only(config) {
+    Ok(secrets) => {
+      let secrets = Arc::new(secrets);
+
+      // Builds the uploaders and pipelines the way the service does, which
+      // catches what the checks above cannot, like an unparsable key file.
+      for (location, destination) in resolvable {
+        if let Err(err) = config.destination(destination, &secrets).await {
+          problems.push(&location, format!("{err:#}"));
+        }
+      }
+    }
+
+    Err(err) => problems.push("secrets", format!("{err:#}")),
+  }
+
+  for problem in problems.0.iter_mut() {
+    problem.position = locate(&config.source, &problem.location);
+  }
+
+  problems.0
+}
+
+fn validate_destination(problems: &mut Problems, location: &str, destination: &DestinationConfig) {
+  if !secrets::is_valid_name(&destination.name()) {
+    problems.push(&format!("{location}.name"), "must not be empty, start with '.' or contain '/' or '\\'");
+  }
+
+  match destination.uploader {
+    UploaderKind::Noop => {}
+
+    UploaderKind::S3(ref config) => {
+      problems.url(location, "endpoint", &config.endpoint);
+      problems.required(location, "bucket", &config.bucket);
+      problems.required(location, "access_key_id", &config.access_key_id);
+      problems.required(location, "secret_access_key", &config.secret_access_key);
+    }
+
+    UploaderKind::GoogleDrive(ref config) => match config.auth {
+      GoogleDriveAuth::ServiceAccount => {
+        match config.private_key_file {
+          Some(ref path) => problems.file(location, "private_key_file", path),
+          None => problems.push(&format!("{location}.private_key_file"), "required with service accounts"),
+        }
+
+        if config.folder.is_none() {
+          problems.push(&format!("{location}.folder"), "required with service accounts");
+        }
+      }
+
+      GoogleDriveAuth::Oauth => match config.client_secret_file {
+        Some(ref path) => problems.file(location, "client_secret_file", path),
+        None => problems.push(&format!("{location}.client_secret_file"), "required with OAuth"),
+      },
+    },
+
+    UploaderKind::Dropbox(ref config) => {
+      problems.required(location, "client_id", &config.client_id);
+
+      if let Some(ref redirect_uri) = config.redirect_uri {
+        problems.url(location, "redirect_uri", redirect_uri);
+      }
+    }
+
+    UploaderKind::OneDrive(ref config) => {
+      problems.required(location, "client_id", &config.client_id);
+
+      match (config.flow, &config.client_secret) {
+        (AuthFlow::Redirect, Some(client_secret)) => problems.required(location, "client_secret", client_secret),
+        (AuthFlow::Redirect, None) => problems.push(&format!("{location}.client_secret"), "required with the redirect flow"),
+        (AuthFlow::Device, _) => {}
+      }
+
+      problems.url(location, "redirect_uri", &config.redirect_uri);
+    }
+
+    UploaderKind::Imgur(ref config) => {
+      problems.required(location, "client_id", &config.client_id);
+      problems.required(location, "client_secret", &config.client_secret);
+      problems.url(location, "redirect_uri", &config.redirect_uri);
+    }
+
+    UploaderKind::Discord(ref config) => {
+      problems.required(location, "token", &config.token);
+
+      if config.channel == 0 {
+        problems.push(&format!("{location}.channel"), "must be a channel ID");
+      }
+    }
+  }
+
+  if let Some(ref overlay) = destination.overlay {
+    if !(0.0..=1.0).contains(&overlay.opacity) {
+      problems.push(&format!("{location}.overlay.opacity"), "must be between 0 and 1");
+    }
+
+    if overlay.font_size <= 0.0 {
+      problems.push(&format!("{location}.overlay.font_size"), "must be positive");
+    }
+
+    match overlay.font {
+      Some(ref font) => problems.file(&format!("{location}.overlay"), "font", font),
+      None if overlay.font().is_none() => problems.push(
+        &format!("{location}.overlay.font"),
+        format!("no default font installed ({}), set the path of a TrueType font", DEFAULT_FONTS.join(", ")),
+      ),
+      None => {}
+    }
+  }
+
+  problems.rate(&format!("{location}.bandwidth_limit"), destination.bandwidth_limit);
+
+  if let Some(ref thumbnail) = destination.thumbnail {
+    if thumbnail.size == 0 {
+      problems.push(&format!("{location}.thumbnail.size"), "must be positive");
+    }
+  }
+}
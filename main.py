@@ -1,4 +1,5 @@
 import asyncio
+import json
 import os
 import pathlib
 import logging
@@ -10,6 +11,11 @@ PLUGIN_DIR = pathlib.Path(__file__).parent.resolve()
 CONFIG_DIR = pathlib.Path("/home/deck/.config/deckshot")
 CONFIG_FILE = CONFIG_DIR / "deckshot.yml"
 
+DEFAULT_SHUTDOWN_GRACE_PERIOD = 10
+# Time left to deckshot, past its grace period, to queue the interrupted
+# uploads and exit.
+SHUTDOWN_MARGIN = 5
+
 sys.path.insert(0, str(PLUGIN_DIR / "py_modules"))
 
 import yaml
@@ -56,8 +62,20 @@ class Plugin:
         if await self.is_running(self):
             log("stopping backend service")
 
+            timeout = await self.shutdown_timeout(self)
+
             self.process.terminate()
 
+            # Uploads in progress are given time to finish, and another
+            # instance cannot start until this one exits.
+            for _ in range(int(timeout * 10)):
+                if self.process.poll() is not None:
+                    break
+
+                await asyncio.sleep(0.1)
+            else:
+                self.process.kill()
+
         self.process = None
 
     async def toggle(self):
@@ -71,12 +89,45 @@ class Plugin:
         else:
             await self.start(self)
 
+    async def control(self, command, **arguments):
+        socket_file = await self.socket_file(self)
+        reader, writer = await asyncio.open_unix_connection(str(socket_file))
+
+        try:
+            request = json.dumps({"command": command, **arguments}) + "\n"
+            writer.write(request.encode())
+            await writer.drain()
+
+            return json.loads(await reader.readline())
+        finally:
+            writer.close()
+            await writer.wait_closed()
+
     async def is_running(self):
         if self.process is None:
             return False
 
         return self.process.poll() is None
 
+    async def shutdown_timeout(self):
+        try:
+            config = await self.get_config(self) or {}
+            grace_period = float(config.get("shutdown_grace_period", DEFAULT_SHUTDOWN_GRACE_PERIOD))
+        except (OSError, yaml.YAMLError, AttributeError, TypeError, ValueError):
+            grace_period = DEFAULT_SHUTDOWN_GRACE_PERIOD
+
+        return max(grace_period, 0) + SHUTDOWN_MARGIN
+
+    async def socket_file(self):
+        try:
+            config = await self.get_config(self) or {}
+            socket = config.get("socket")
+            deckshot_path = config.get("deckshot_path") or CONFIG_DIR
+        except (OSError, yaml.YAMLError, AttributeError):
+            socket, deckshot_path = None, CONFIG_DIR
+
+        return pathlib.Path(socket) if socket else pathlib.Path(deckshot_path) / "deckshot.sock"
+
     async def get_config(self):
         with open(CONFIG_FILE) as f:
             return yaml.safe_load(f)
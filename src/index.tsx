@@ -13,7 +13,8 @@ import { VFC, useState, useEffect } from "react";
 import { FaCamera } from "react-icons/fa";
 
 type Config = {
-  uploader: { [name: string]: any }
+  uploader?: { [name: string]: any },
+  uploaders?: { [name: string]: any }[]
 };
 
 const Status: VFC<{ api: ServerAPI }> = ({ api }) => {
@@ -56,6 +57,73 @@ const Status: VFC<{ api: ServerAPI }> = ({ api }) => {
   );
 };
 
+type Response<T> = {
+  ok: boolean,
+  data?: T,
+  error?: string
+};
+
+type DaemonStatus = {
+  paused: boolean,
+  queued: number,
+  held: string | null
+};
+
+const Queue: VFC<{ api: ServerAPI }> = ({ api }) => {
+  const [status, setStatus] = useState<DaemonStatus | null>(null);
+
+  const control = async <T,>(command: string, args: { [name: string]: any } = {}): Promise<T | null> => {
+    const result = await api?.callPluginMethod<any, Response<T>>("control", { command, ...args });
+
+    return result?.success && result.result.ok ? result.result.data ?? null : null;
+  };
+
+  const fetchStatus = async () => {
+    setStatus(await control<DaemonStatus>("status"));
+  };
+
+  useEffect(() => {
+    fetchStatus();
+
+    const interval = setInterval(() => {
+      fetchStatus();
+    }, 5000);
+
+    return () => clearInterval(interval);
+  }, []);
+
+  if (!status) {
+    return null;
+  }
+
+  return (
+    <>
+      <PanelSectionRow>
+        <ToggleField
+          label="Pause uploads"
+          description={`${status.queued} screenshot(s) waiting to be uploaded${status.held && !status.paused ? ` (${status.held})` : ""}`}
+          checked={status.paused}
+          onChange={async (paused) => {
+            await control(paused ? "pause" : "resume");
+            await fetchStatus();
+          }}
+        />
+      </PanelSectionRow>
+
+      {status.queued > 0 && (
+        <PanelSectionRow>
+          <DialogButton onClick={async () => {
+            await control("retry");
+            await fetchStatus();
+          }}>
+            Retry now
+          </DialogButton>
+        </PanelSectionRow>
+      )}
+    </>
+  );
+};
+
 const Content: VFC<{ api: ServerAPI }> = ({ api }) => {
   const [config, setConfig] = useState<Config | null>(null);
 
@@ -74,6 +142,7 @@ const Content: VFC<{ api: ServerAPI }> = ({ api }) => {
   return (
     <PanelSection title="Configuration">
       <Status api={api} />
+      <Queue api={api} />
 
       <PanelSectionRow>
         <div style={{ padding: "8px 0", fontSize: "0.8em" }}>
@@ -87,8 +156,8 @@ const Content: VFC<{ api: ServerAPI }> = ({ api }) => {
 
       <PanelSectionRow>
         <Field
-          label="Uploader"
-          description={config?.uploader?.kind ?? "N/A"}
+          label="Uploaders"
+          description={[config?.uploader, ...(config?.uploaders ?? [])].filter((uploader) => uploader).map((uploader) => uploader?.name ?? uploader?.kind).join(", ") || "N/A"}
         />
       </PanelSectionRow>
     </PanelSection>